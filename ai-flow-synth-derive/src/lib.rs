@@ -0,0 +1,131 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, Ident, ext::IdentExt, parse_macro_input, spanned::Spanned};
+
+/// `#[derive(Status)]` on an enum of unit variants implements `Status`, `StatusNames`,
+/// `Display` (the variant name) and `FromStr` (from the variant name), plus `Default` if
+/// a variant is marked `#[status(default)]`. Exactly one variant must be marked
+/// `#[status(failed)]`. The name of a raw identifier like `r#Type` is `Type`.
+///
+/// ```ignore
+/// #[derive(Debug, Clone, PartialEq, Status)]
+/// enum JobStatus {
+///     #[status(default)]
+///     NotStarted,
+///     #[status(failed)]
+///     Failed,
+///     Written,
+/// }
+/// ```
+#[proc_macro_derive(Status, attributes(status))]
+pub fn derive_status(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            "Status can only be derived for enums",
+        ));
+    };
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut variants = Vec::new();
+    let mut failed = None;
+    let mut default = None;
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(syn::Error::new(
+                variant.span(),
+                "Status variants can not have fields",
+            ));
+        }
+        for attr in variant.attrs.iter().filter(|a| a.path().is_ident("status")) {
+            attr.parse_nested_meta(|meta| {
+                let slot = if meta.path.is_ident("failed") {
+                    &mut failed
+                } else if meta.path.is_ident("default") {
+                    &mut default
+                } else {
+                    return Err(meta.error("expected `failed` or `default`"));
+                };
+                if slot.is_some() {
+                    return Err(meta.error("only one variant can be marked like this"));
+                }
+                *slot = Some(variant.ident.clone());
+                Ok(())
+            })?;
+        }
+        variants.push(&variant.ident);
+    }
+    let Some(failed) = failed else {
+        return Err(syn::Error::new(
+            input.span(),
+            "mark the failed variant with #[status(failed)]",
+        ));
+    };
+
+    let names = variants
+        .iter()
+        .map(|v| v.unraw().to_string())
+        .collect::<Vec<_>>();
+    let default_impl = default.map(|default: Ident| {
+        quote! {
+            impl #impl_generics ::core::default::Default for #name #ty_generics #where_clause {
+                fn default() -> Self {
+                    #name::#default
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::ai_flow_synth::core::status::Status for #name #ty_generics #where_clause {
+            fn failed() -> Self {
+                #name::#failed
+            }
+
+            fn label(&self) -> ::core::option::Option<&'static str> {
+                ::core::option::Option::Some(
+                    ::ai_flow_synth::core::status::StatusNames::name(self),
+                )
+            }
+        }
+
+        #default_impl
+
+        impl #impl_generics ::ai_flow_synth::core::status::StatusNames for #name #ty_generics #where_clause {
+            const NAMES: &'static [&'static str] = &[#(#names),*];
+
+            fn name(&self) -> &'static str {
+                match self {
+                    #(#name::#variants => #names,)*
+                }
+            }
+        }
+
+        impl #impl_generics ::core::fmt::Display for #name #ty_generics #where_clause {
+            fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                f.write_str(::ai_flow_synth::core::status::StatusNames::name(self))
+            }
+        }
+
+        impl #impl_generics ::core::str::FromStr for #name #ty_generics #where_clause {
+            type Err = ::ai_flow_synth::core::status::ParseStatusError;
+
+            fn from_str(s: &str) -> ::core::result::Result<Self, Self::Err> {
+                match s {
+                    #(#names => ::core::result::Result::Ok(#name::#variants),)*
+                    _ => ::core::result::Result::Err(
+                        ::ai_flow_synth::core::status::ParseStatusError(s.to_owned()),
+                    ),
+                }
+            }
+        }
+    })
+}
@@ -1,11 +1,425 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::llm::tool::ToolRegistry;
+
+use super::{
+    context::{Context, ContextKey},
+    error::FlowError,
+    event_log::MessageStream,
+    flow::Flow,
+    report::FlowRunReport,
+    status::Status,
+    template::{FlowTemplate, NodeRegistry},
+};
+
+/// the input of the run, checked against `Agent::input_schema`
+pub const AGENT_INPUT: ContextKey<Value> = ContextKey::new("agent.input");
+/// the agent's `llm` settings, for its nodes to create their clients with
+pub const AGENT_LLM: ContextKey<LlmSettings> = ContextKey::new("agent.llm");
+/// the names of the agent's tools, the nodes get the tools from [`Context::tools`]
+pub const AGENT_TOOLS: ContextKey<Vec<String>> = ContextKey::new("agent.tools");
 
-// The agent should control the flow of the task,
-// so it should contains all the nodes and the flow of the task
-// also the input and output information
+/// The agent owns everything to run a task: the flow, the input and output
+/// it takes and gives, and the llm and tools its nodes use.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Agent {
     pub id: String,
     pub name: String,
     pub description: String,
+    pub template: FlowTemplate,
+    /// JSON schema of the input, `null` takes any input
+    #[serde(default)]
+    pub input_schema: Value,
+    /// JSON schema of the flow result, `null` takes any result
+    #[serde(default)]
+    pub output_schema: Value,
+    #[serde(default)]
+    pub llm: LlmSettings,
+    #[serde(default)]
+    pub tools: Vec<String>,
+}
+
+/// The llm defaults of an agent, unset fields are up to the node.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LlmSettings {
+    /// e.g. `deepseek` or `openai`
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub base_url: Option<String>,
+    pub system_prompt: Option<String>,
+}
+
+impl Agent {
+    pub fn new(id: &str, name: &str, template: FlowTemplate) -> Self {
+        Agent {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            description: String::new(),
+            template,
+            input_schema: Value::Null,
+            output_schema: Value::Null,
+            llm: LlmSettings::default(),
+            tools: Vec::new(),
+        }
+    }
+
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.description = description.to_owned();
+        self
+    }
+
+    /// take the schema of `T` as the input schema
+    pub fn with_input<T: JsonSchema>(mut self) -> Self {
+        self.input_schema = schemars::schema_for!(T).to_value();
+        self
+    }
+
+    /// take the schema of `T` as the output schema
+    pub fn with_output<T: JsonSchema>(mut self) -> Self {
+        self.output_schema = schemars::schema_for!(T).to_value();
+        self
+    }
+
+    pub fn with_llm(mut self, llm: LlmSettings) -> Self {
+        self.llm = llm;
+        self
+    }
+
+    pub fn with_tools(mut self, tools: &[&str]) -> Self {
+        self.tools = tools.iter().map(|tool| tool.to_string()).collect();
+        self
+    }
+}
+
+/// An agent with its flow built, get it from [`AgentRegistry::get`].
+pub struct LoadedAgent<S: Status> {
+    pub agent: Agent,
+    flow: Arc<Flow<S>>,
+    /// the agent's tools of the `AgentRegistry`
+    tools: Arc<ToolRegistry>,
+    input_schema: Schema,
+    output_schema: Arc<Schema>,
+}
+
+/// A started agent run, read its messages from `events`, then await `result`.
+pub struct AgentRun {
+    /// the id of the run's context
+    pub run_id: String,
+    /// every message of the run from the start, ends with the run
+    pub events: MessageStream,
+    pub cancel: CancellationToken,
+    pub result: JoinHandle<(anyhow::Result<Value>, FlowRunReport)>,
+}
+
+impl<S: Status + 'static> LoadedAgent<S> {
+    /// Check `input`, then run the flow in the background with the input and
+    /// the agent's settings in the context, see [`AGENT_INPUT`].
+    pub fn run(&self, input: Value) -> anyhow::Result<AgentRun> {
+        self.input_schema
+            .check(&input, "input")
+            .map_err(|e| FlowError::Agent(format!("'{}' got an invalid {e}", self.agent.id)))?;
+        let mut context = Context::new();
+        context.set_key(&AGENT_INPUT, &input)?;
+        context.set_key(&AGENT_LLM, &self.agent.llm)?;
+        context.set_key(&AGENT_TOOLS, &self.agent.tools)?;
+        context.set_tools(self.tools.clone());
+
+        let flow = self.flow.clone();
+        let agent_id = self.agent.id.clone();
+        let output_schema = self.output_schema.clone();
+        let run = AgentRun {
+            run_id: context.id().to_owned(),
+            events: context.listen_from(0),
+            cancel: context.cancellation_token().clone(),
+            result: tokio::spawn(async move {
+                // the run only finishes with a valid output
+                let check = |output: &Value| {
+                    output_schema.check(output, "output").map_err(|e| {
+                        FlowError::Agent(format!("'{agent_id}' gave an invalid {e}")).into()
+                    })
+                };
+                flow.run_checked(context, check).await
+            }),
+        };
+        Ok(run)
+    }
+}
+
+/// The agents of an application by id, their flows are built from the templates
+/// with one `NodeRegistry`, their tools come from one `ToolRegistry`.
+pub struct AgentRegistry<S: Status> {
+    nodes: NodeRegistry<S>,
+    tools: Arc<ToolRegistry>,
+    agents: HashMap<String, Arc<LoadedAgent<S>>>,
+}
+
+impl<S: Status> std::fmt::Debug for AgentRegistry<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AgentRegistry")
+            .field("agents", &self.agents.keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: Status + FromStr> AgentRegistry<S> {
+    pub fn new(nodes: NodeRegistry<S>, tools: Arc<ToolRegistry>) -> Self {
+        AgentRegistry {
+            nodes,
+            tools,
+            agents: HashMap::new(),
+        }
+    }
+
+    /// Build the agent's flow and compile its schemas, fails if one is invalid or
+    /// the agent uses an unknown tool.
+    /// An agent with the same id is replaced.
+    pub fn register(&mut self, agent: Agent) -> anyhow::Result<()> {
+        if let Some(tool) = agent
+            .tools
+            .iter()
+            .find(|tool| self.tools.get(tool).is_none())
+        {
+            return Err(
+                FlowError::Agent(format!("'{}' uses unknown tool '{tool}'", agent.id)).into(),
+            );
+        }
+        let schema = |schema: &Value, name: &str| {
+            Schema::new(schema).map_err(|e| {
+                FlowError::Agent(format!("'{}' has an invalid {name} schema: {e}", agent.id))
+            })
+        };
+        let input_schema = schema(&agent.input_schema, "input")?;
+        let output_schema = Arc::new(schema(&agent.output_schema, "output")?);
+        let flow = Arc::new(self.nodes.build(&agent.template)?);
+        let loaded = LoadedAgent {
+            tools: Arc::new(self.tools.subset(&agent.tools)),
+            agent,
+            flow,
+            input_schema,
+            output_schema,
+        };
+        self.agents
+            .insert(loaded.agent.id.clone(), Arc::new(loaded));
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<LoadedAgent<S>>> {
+        self.agents.get(id).cloned()
+    }
+
+    /// all agents, sorted by id
+    pub fn list(&self) -> Vec<&Agent> {
+        let mut agents = self
+            .agents
+            .values()
+            .map(|loaded| &loaded.agent)
+            .collect::<Vec<_>>();
+        agents.sort_by(|a, b| a.id.cmp(&b.id));
+        agents
+    }
+
+    pub fn tools(&self) -> &Arc<ToolRegistry> {
+        &self.tools
+    }
+}
+
+/// A compiled `Agent::input_schema` or `Agent::output_schema`, `null` takes any value.
+struct Schema(Option<jsonschema::Validator>);
+
+impl Schema {
+    fn new(schema: &Value) -> Result<Self, String> {
+        if schema.is_null() {
+            return Ok(Schema(None));
+        }
+        jsonschema::validator_for(schema)
+            .map(|validator| Schema(Some(validator)))
+            .map_err(|e| e.to_string())
+    }
+
+    /// the first error of `value`, at its path in `name`
+    fn check(&self, value: &Value, name: &str) -> Result<(), String> {
+        match self.0.as_ref().and_then(|v| v.iter_errors(value).next()) {
+            Some(e) => Err(format!("{name}{}: {e}", e.instance_path())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::core::{node::Node, report::RunStatus, stream_message::StreamMessage};
+
+    #[derive(Debug, PartialEq, Status)]
+    enum MyStatus {
+        #[status(default)]
+        Done,
+        #[status(failed)]
+        Failed,
+    }
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    #[allow(dead_code)]
+    struct Topic {
+        topic: String,
+        words: Option<u32>,
+    }
+
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    #[allow(dead_code)]
+    struct Story {
+        title: String,
+    }
+
+    /// writes a story about the input topic, or a wrongly shaped one for "broken"
+    struct TitleNode {}
+    #[async_trait::async_trait]
+    impl Node for TitleNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            let input = context.get_key(&AGENT_INPUT)?;
+            let llm = context.get_key(&AGENT_LLM)?;
+            let topic = input["topic"].as_str().unwrap_or_default();
+            if topic == "broken" {
+                return Ok(Value::from(topic));
+            }
+            let mut tools = context.tools().map.keys().copied().collect::<Vec<_>>();
+            tools.sort();
+            Ok(serde_json::json!({
+                "title": format!(
+                    "{topic} by {} with {}",
+                    llm.model.unwrap_or_default(),
+                    tools.join(", ")
+                ),
+            }))
+        }
+    }
+
+    static TEMPLATE: &str = r#"
+name = "story"
+start = "title"
+
+[[nodes]]
+name = "title"
+type = "title"
+"#;
+
+    #[tokio::test]
+    async fn test_agent_run() {
+        let mut nodes = NodeRegistry::new();
+        nodes.register("title", |_: Value| {
+            Arc::new(TitleNode {}) as Arc<dyn Node<FlowStatus = MyStatus>>
+        });
+        let mut tools = ToolRegistry::new();
+        tools.register("outline", "Outline a story", |topic: Topic| {
+            Value::from(topic.topic)
+        });
+        tools.register("rhyme", "Find a rhyme", |story: Story| {
+            Value::from(story.title)
+        });
+        let mut registry = AgentRegistry::new(nodes, Arc::new(tools));
+        let template = FlowTemplate::from_toml(TEMPLATE).unwrap();
+        let agent = Agent::new("story", "Story writer", template)
+            .with_input::<Topic>()
+            .with_output::<Story>()
+            .with_llm(LlmSettings {
+                model: Some("deepseek-chat".to_owned()),
+                ..Default::default()
+            });
+        assert!(
+            registry
+                .register(agent.clone().with_tools(&["missing"]))
+                .is_err()
+        );
+        registry.register(agent.with_tools(&["outline"])).unwrap();
+        assert_eq!(registry.list().len(), 1);
+
+        let story = registry.get("story").unwrap();
+        assert!(story.run(serde_json::json!({ "words": 10 })).is_err());
+        assert!(
+            story
+                .run(serde_json::json!({ "topic": "robots", "words": "ten" }))
+                .is_err()
+        );
+
+        let run = story.run(serde_json::json!({ "topic": "robots" })).unwrap();
+        let events = run.events.collect::<Vec<_>>().await;
+        assert!(matches!(
+            events.first().map(|e| &e.message),
+            Some(StreamMessage::NodeStarted { .. })
+        ));
+        let (result, _) = run.result.await.unwrap();
+        assert_eq!(
+            result.unwrap(),
+            serde_json::json!({ "title": "robots by deepseek-chat with outline" })
+        );
+
+        // the run fails on the output and only then finishes
+        let run = story.run(serde_json::json!({ "topic": "broken" })).unwrap();
+        let events = run.events.collect::<Vec<_>>().await;
+        let (result, report) = run.result.await.unwrap();
+        assert!(result.unwrap_err().to_string().contains("invalid output"));
+        assert_eq!(report.status, RunStatus::Failed);
+        let last = events.iter().rev().map(|e| &e.message).collect::<Vec<_>>();
+        assert!(
+            matches!(last[1], StreamMessage::Error { message } if message.contains("invalid output"))
+        );
+        assert!(matches!(
+            last[0],
+            StreamMessage::FlowFinished {
+                status: RunStatus::Failed,
+                error: Some(_)
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_agent_schema() {
+        let mut nodes = NodeRegistry::new();
+        nodes.register("title", |_: Value| {
+            Arc::new(TitleNode {}) as Arc<dyn Node<FlowStatus = MyStatus>>
+        });
+        let mut registry = AgentRegistry::new(nodes, Arc::new(ToolRegistry::new()));
+        let template = FlowTemplate::from_toml(TEMPLATE).unwrap();
+        let mut agent = Agent::new("story", "Story writer", template);
+        agent.input_schema = serde_json::json!({ "type": "object", "required": 1 });
+        assert!(registry.register(agent.clone()).is_err());
+
+        agent.input_schema = serde_json::json!({
+            "$defs": { "topic": { "type": "string", "minLength": 1 } },
+            "type": "object",
+            "properties": {
+                "topic": { "$ref": "#/$defs/topic" },
+                "words": { "anyOf": [{ "type": "integer" }, { "type": "null" }] },
+            },
+            "additionalProperties": false,
+        });
+        registry.register(agent).unwrap();
+        let story = registry.get("story").unwrap();
+        for input in [
+            serde_json::json!({ "topic": "" }),
+            serde_json::json!({ "topic": "robots", "words": "ten" }),
+            serde_json::json!({ "topic": "robots", "style": "noir" }),
+        ] {
+            let e = story.run(input).err().unwrap();
+            assert!(e.to_string().contains("invalid input"));
+        }
+        let run = story
+            .run(serde_json::json!({ "topic": "robots", "words": null }))
+            .unwrap();
+        assert!(run.result.await.unwrap().0.is_ok());
+    }
 }
@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use futures::StreamExt;
+use serde_json::Value;
+
+use super::{
+    context::{CONTEXT_RESULT, Context},
+    node::{Node, run_node},
+    status::Status,
+    stream_message::StreamMessage,
+};
+
+pub static CONTEXT_BATCH_ITEM: &str = "item";
+/// the progress stream of a batch node that runs outside of a flow
+pub static BATCH_STREAM: &str = "batch";
+
+/// Run the inner node once for every item of the JSON array at `input_key`.
+///
+/// Every item runs on its own copy of the context with the item set at `item_key`,
+/// the results (the `CONTEXT_RESULT` each run left) are written to `output_key`
+/// in input order, as `{"value": ...}` or `{"error": "..."}` for each item.
+/// The llm tokens the items used are added to the context. The progress is sent to
+/// the stream named like the node, unless another one is set by `with_stream`.
+pub struct BatchNode<S: Status> {
+    inner: Arc<dyn Node<FlowStatus = S>>,
+    input_key: String,
+    output_key: String,
+    item_key: String,
+    concurrency: usize,
+    stream: Option<String>,
+}
+
+impl<S: Status> BatchNode<S> {
+    pub fn new(inner: Arc<dyn Node<FlowStatus = S>>, input_key: &str, output_key: &str) -> Self {
+        BatchNode {
+            inner,
+            input_key: input_key.to_owned(),
+            output_key: output_key.to_owned(),
+            item_key: CONTEXT_BATCH_ITEM.to_owned(),
+            concurrency: 4,
+            stream: None,
+        }
+    }
+
+    pub fn with_item_key(mut self, item_key: &str) -> Self {
+        self.item_key = item_key.to_owned();
+        self
+    }
+
+    /// the stream of the progress messages
+    pub fn with_stream(mut self, stream: &str) -> Self {
+        self.stream = Some(stream.to_owned());
+        self
+    }
+
+    /// max number of items running at the same time
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// the result of the item and the llm tokens it used
+    async fn run_item(&self, mut context: Context, item: Value) -> (Result<Value, String>, i64) {
+        let token_usage = context.token_usage();
+        context.remove(CONTEXT_RESULT);
+        context.set(&self.item_key, item);
+        let result = match run_node(self.inner.as_ref(), &mut context).await {
+            Ok(result) if result.status == S::failed() => Err(result.message),
+            Ok(_) => Ok(context.get(CONTEXT_RESULT).cloned().unwrap_or_default()),
+            Err(e) => Err(e.to_string()),
+        };
+        (result, context.token_usage() - token_usage)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Status + 'static> Node for BatchNode<S> {
+    type FlowStatus = S;
+
+    async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+        let items = match context.get(&self.input_key) {
+            Some(Value::Array(items)) => items.clone(),
+            Some(_) => anyhow::bail!("batch input '{}' is not an array", self.input_key),
+            None => anyhow::bail!("batch input '{}' not found", self.input_key),
+        };
+        let stream = match (self.stream.as_deref(), context.node()) {
+            (Some(stream), _) | (None, Some(stream)) => stream.to_owned(),
+            (None, None) => BATCH_STREAM.to_owned(),
+        };
+        let stream = context.stream(&stream);
+        let total = items.len();
+
+        let base = context.clone();
+        let mut runs = futures::stream::iter(items.into_iter().enumerate())
+            .map(|(index, item)| {
+                let item_context = base.clone();
+                async move { (index, self.run_item(item_context, item).await) }
+            })
+            .buffer_unordered(self.concurrency);
+
+        let mut results = vec![Value::Null; total];
+        let mut done = 0;
+        while let Some((index, (result, token_usage))) = runs.next().await {
+            done += 1;
+            context.add_token_usage(token_usage);
+            results[index] = match result {
+                Ok(value) => serde_json::json!({ "value": value }),
+                Err(e) => {
+                    stream.send(StreamMessage::Procedure(format!(
+                        "Batch item {index} failed: {e}"
+                    )));
+                    serde_json::json!({ "error": e })
+                }
+            };
+            stream.send(StreamMessage::Procedure(format!("Batch {done}/{total}")));
+        }
+
+        let results = Value::Array(results);
+        context.set(&self.output_key, results.clone());
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::core::stream_message::TaggedMessage;
+
+    #[derive(Debug, PartialEq, Status)]
+    enum MyStatus {
+        #[status(default)]
+        Done,
+        #[status(failed)]
+        Failed,
+    }
+
+    struct DoubleNode {}
+    #[async_trait::async_trait]
+    impl Node for DoubleNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            let item = context.get(CONTEXT_BATCH_ITEM).and_then(Value::as_i64);
+            context.add_token_usage(10);
+            match item {
+                Some(n) if n >= 0 => Ok(Value::from(n * 2)),
+                _ => anyhow::bail!("invalid item"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_node() {
+        let batch =
+            BatchNode::new(Arc::new(DoubleNode {}), "numbers", "doubled").with_concurrency(2);
+        let mut context = Context::new();
+        context.set_node(Some("double".to_owned()));
+        let mut listener = context.listen();
+        context.add_token_usage(5);
+        context.set(CONTEXT_RESULT, Value::from("stale"));
+        context.set("numbers", serde_json::json!([1, -1, 3]));
+
+        let result = batch.execute(&mut context).await.unwrap();
+        let expected = serde_json::json!([
+            { "value": 2 },
+            { "error": "invalid item" },
+            { "value": 6 },
+        ]);
+        assert_eq!(result, expected);
+        assert_eq!(context.get("doubled"), Some(&expected));
+        assert_eq!(context.token_usage(), 5 + 3 * 10);
+
+        let mut progress = Vec::new();
+        while let Ok(Some(TaggedMessage {
+            stream,
+            message: StreamMessage::Procedure(p),
+            ..
+        })) =
+            tokio::time::timeout(std::time::Duration::from_millis(10), listener.next()).await
+        {
+            progress.push((stream, p));
+        }
+        let last = progress.last().unwrap();
+        assert_eq!((last.0.as_str(), last.1.as_str()), ("double", "Batch 3/3"));
+
+        let batch = batch.with_stream("progress");
+        batch.execute(&mut context).await.unwrap();
+        let message = listener.next().await.unwrap();
+        assert_eq!(message.stream, "progress");
+
+        context.set("numbers", Value::Null);
+        assert!(batch.execute(&mut context).await.is_err());
+    }
+}
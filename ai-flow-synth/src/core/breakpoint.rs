@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+/// A run paused after a breakpoint node, the run continues once this is resumed (or dropped).
+#[derive(Debug)]
+pub struct PausedNode {
+    pub node: String,
+    /// the context values the node set or changed
+    pub writes: HashMap<String, Value>,
+    reply: oneshot::Sender<HashMap<String, Value>>,
+}
+
+impl PausedNode {
+    pub(crate) fn new(
+        node: String,
+        writes: HashMap<String, Value>,
+    ) -> (Self, oneshot::Receiver<HashMap<String, Value>>) {
+        let (reply, edits) = oneshot::channel();
+        (
+            PausedNode {
+                node,
+                writes,
+                reply,
+            },
+            edits,
+        )
+    }
+
+    /// continue with the values as the node wrote them
+    pub fn resume(self) {
+        self.resume_with(HashMap::new());
+    }
+
+    /// set `edits` into the context, then continue to the next edge
+    pub fn resume_with(self, edits: HashMap<String, Value>) {
+        // the run is gone if the receiver is dropped, nothing to resume then
+        let _ = self.reply.send(edits);
+    }
+}
@@ -0,0 +1,494 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use mongodb::{
+    IndexModel,
+    bson::{DateTime, doc},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::utils::{MongoClient, stable_hash};
+
+use super::{
+    context::Context,
+    node::{Node, execute_retried},
+    retry::{RETRY_STREAM, RetryPolicy},
+    status::Status,
+    stream_message::{FLOW_STREAM, StreamMessage},
+};
+
+pub static NODE_CACHE_COLLECTION: &str = "node_cache";
+
+/// the outputs [`NodeCache::memory`] keeps at most
+pub const MEMORY_CACHE_CAPACITY: usize = 1024;
+
+/// What the output of a cached node depends on besides its name, its type and
+/// `Node::cache_config`, see `Flow::set_cache`.
+#[derive(Debug, Clone, Default)]
+pub struct CachePolicy {
+    /// the context keys the node reads, their values are part of the key
+    pub reads: Vec<String>,
+    /// how long an output is used, `None` means until it is removed
+    pub ttl: Option<Duration>,
+    /// e.g. the name of the flow, the outputs are only shared within a namespace
+    pub namespace: String,
+}
+
+impl CachePolicy {
+    pub fn new(reads: &[&str]) -> Self {
+        CachePolicy {
+            reads: reads.iter().map(|key| key.to_string()).collect(),
+            ttl: None,
+            namespace: String::new(),
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = namespace.to_owned();
+        self
+    }
+
+    /// the key of `node`, named `name`, on the current values of `context`
+    pub fn key<S: Status>(
+        &self,
+        name: &str,
+        node: &dyn Node<FlowStatus = S>,
+        context: &Context,
+    ) -> String {
+        let reads = self
+            .reads
+            .iter()
+            .map(|key| (key.as_str(), context.get(key)))
+            .collect::<Vec<_>>();
+        let key = serde_json::json!({
+            "namespace": self.namespace,
+            "node": name,
+            "type": node.type_name(),
+            "config": node.cache_config(),
+            "reads": reads,
+        });
+        stable_hash(key.to_string().as_bytes())
+    }
+}
+
+/// One cached execution of a node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedOutput {
+    pub key: String,
+    pub node: String,
+    /// the value `execute` returned
+    pub output: Value,
+    /// what `execute` wrote to the context
+    pub writes: HashMap<String, Value>,
+    /// what the node sent to its streams, `(stream, message)`
+    pub messages: Vec<(String, StreamMessage)>,
+    pub expires_at: Option<DateTime>,
+}
+
+impl CachedOutput {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= DateTime::now())
+    }
+
+    /// apply the writes and send the messages again, as if the node ran
+    fn replay(&self, context: &mut Context) {
+        for (key, value) in &self.writes {
+            context.set(key, value.clone());
+        }
+        context
+            .stream(FLOW_STREAM)
+            .send(StreamMessage::Procedure(format!(
+                "cached output of {}",
+                self.node
+            )));
+        for (stream, message) in &self.messages {
+            context.stream(stream).send(message.clone());
+        }
+    }
+}
+
+/// The outputs of [`NodeCache::Memory`]. The expired ones are dropped on every `put`,
+/// the oldest ones are evicted to keep at most `capacity`.
+#[derive(Debug)]
+pub struct MemoryOutputs {
+    outputs: HashMap<String, CachedOutput>,
+    /// the keys in the order they were put
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl MemoryOutputs {
+    fn insert(&mut self, output: CachedOutput) {
+        if self.outputs.remove(&output.key).is_some() {
+            self.order.retain(|key| *key != output.key);
+        }
+        if self.outputs.values().any(CachedOutput::is_expired) {
+            self.outputs.retain(|_, output| !output.is_expired());
+            self.order.retain(|key| self.outputs.contains_key(key));
+        }
+        while self.outputs.len() >= self.capacity {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.outputs.remove(&oldest);
+        }
+        self.order.push_back(output.key.clone());
+        self.outputs.insert(output.key.clone(), output);
+    }
+
+    fn remove(&mut self, key: &str) {
+        if self.outputs.remove(key).is_some() {
+            self.order.retain(|k| k != key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.outputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+}
+
+/// Where the outputs of cached nodes are kept, shared by all runs of the flows it is added to.
+#[derive(Debug, Clone)]
+pub enum NodeCache {
+    Memory(Arc<Mutex<MemoryOutputs>>),
+    Mongo(mongodb::Collection<CachedOutput>),
+}
+
+impl NodeCache {
+    /// keeps at most [`MEMORY_CACHE_CAPACITY`] outputs
+    pub fn memory() -> Self {
+        NodeCache::memory_with_capacity(MEMORY_CACHE_CAPACITY)
+    }
+
+    /// keeps at most `capacity` outputs (at least one), the oldest ones are evicted
+    pub fn memory_with_capacity(capacity: usize) -> Self {
+        NodeCache::Memory(Arc::new(Mutex::new(MemoryOutputs {
+            outputs: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity.max(1),
+        })))
+    }
+
+    /// Creates the indexes of the collection, a TTL index lets MongoDB remove
+    /// the expired outputs.
+    pub async fn mongo(client: &MongoClient) -> anyhow::Result<Self> {
+        let collection = client.collection(NODE_CACHE_COLLECTION);
+        let key_index = IndexModel::builder()
+            .keys(doc! { "key": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let ttl_index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build();
+        collection
+            .create_indexes(vec![key_index, ttl_index])
+            .await?;
+        Ok(NodeCache::Mongo(collection))
+    }
+
+    /// the output under `key`, `None` if there is none or it expired
+    pub async fn get(&self, key: &str) -> anyhow::Result<Option<CachedOutput>> {
+        let cached = match self {
+            NodeCache::Memory(outputs) => outputs.lock().unwrap().outputs.get(key).cloned(),
+            NodeCache::Mongo(collection) => collection.find_one(doc! { "key": key }).await?,
+        };
+        match cached {
+            Some(cached) if cached.is_expired() => {
+                self.remove(key).await?;
+                Ok(None)
+            }
+            cached => Ok(cached),
+        }
+    }
+
+    pub async fn put(&self, output: &CachedOutput) -> anyhow::Result<()> {
+        match self {
+            NodeCache::Memory(outputs) => {
+                outputs.lock().unwrap().insert(output.clone());
+            }
+            NodeCache::Mongo(collection) => {
+                collection
+                    .replace_one(doc! { "key": &output.key }, output)
+                    .upsert(true)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn remove(&self, key: &str) -> anyhow::Result<()> {
+        match self {
+            NodeCache::Memory(outputs) => {
+                outputs.lock().unwrap().remove(key);
+            }
+            NodeCache::Mongo(collection) => {
+                collection.delete_one(doc! { "key": key }).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `execute` unless `cache` has an output for the node on this context, a cache that
+/// can't be read or written is logged and skipped.
+pub(crate) async fn execute_cached<S: Status>(
+    node: &dyn Node<FlowStatus = S>,
+    context: &mut Context,
+    (cache, policy): (&NodeCache, &CachePolicy),
+    retry: Option<&RetryPolicy>,
+    timeout: Option<Duration>,
+) -> (anyhow::Result<Value>, Vec<String>) {
+    let name = context.node().unwrap_or_default().to_owned();
+    let key = policy.key(&name, node, context);
+    match cache.get(&key).await {
+        Ok(Some(cached)) => {
+            cached.replay(context);
+            return (Ok(cached.output), Vec::new());
+        }
+        Ok(None) => {}
+        Err(e) => tracing::warn!("Failed to read the cache of node '{name}': {e}"),
+    }
+
+    let before = context.data().clone();
+    let seq = context.event_log().len();
+    let (result, retried) = execute_retried(node, context, retry, timeout).await;
+    if let Ok(output) = &result {
+        let messages = context
+            .event_log()
+            .since(seq)
+            .into_iter()
+            .filter(|msg| msg.node.as_deref() == Some(name.as_str()))
+            .filter(|msg| msg.stream != FLOW_STREAM && msg.stream != RETRY_STREAM)
+            .map(|msg| (msg.stream, msg.message))
+            .collect();
+        let cached = CachedOutput {
+            key,
+            node: name.clone(),
+            output: output.clone(),
+            writes: context.writes_since(&before),
+            messages,
+            expires_at: policy.ttl.map(|ttl| {
+                let ttl = i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX);
+                DateTime::from_millis(DateTime::now().timestamp_millis().saturating_add(ttl))
+            }),
+        };
+        if let Err(e) = cache.put(&cached).await {
+            tracing::warn!("Failed to cache the output of node '{name}': {e}");
+        }
+    }
+    (result, retried)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio_stream::StreamExt;
+
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::{core::context::CONTEXT_RESULT, flow};
+
+    #[derive(Debug, PartialEq, Status)]
+    enum MyStatus {
+        #[status(default)]
+        Done,
+        #[status(failed)]
+        Failed,
+    }
+
+    /// "summarizes" the text, counts its executions
+    struct SummaryNode {
+        executed: AtomicUsize,
+    }
+    #[async_trait::async_trait]
+    impl Node for SummaryNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            self.executed.fetch_add(1, Ordering::SeqCst);
+            let text = context.get_as::<String>("text")?;
+            let summary = text.split(' ').next().unwrap_or_default().to_owned();
+            context
+                .stream("llm")
+                .send(StreamMessage::Delta(summary.clone()));
+            context.set("summary", Value::from(summary.clone()));
+            Ok(Value::from(summary))
+        }
+
+        fn cache_config(&self) -> Value {
+            Value::from("first word")
+        }
+    }
+
+    async fn run(f: &crate::core::flow::Flow<MyStatus>, text: &str) -> (Value, Vec<StreamMessage>) {
+        let mut context = Context::new();
+        context.set("text", Value::from(text));
+        let listener = context.listen_stream("llm");
+        let result = f.run(context).await.unwrap();
+        let messages = listener.map(|msg| msg.message).collect().await;
+        (result, messages)
+    }
+
+    #[tokio::test]
+    async fn test_node_cache() {
+        let node = Arc::new(SummaryNode {
+            executed: AtomicUsize::new(0),
+        });
+        let mut f = flow!(start: ("summary", node.clone())).with_cache(NodeCache::memory());
+        f.set_cache("summary", CachePolicy::new(&["text"]));
+
+        let first = run(&f, "robots dream").await;
+        assert_eq!(first.0, Value::from("robots"));
+        assert_eq!(first.1, vec![StreamMessage::Delta("robots".to_owned())]);
+        assert_eq!(run(&f, "robots dream").await, first);
+        assert_eq!(node.executed.load(Ordering::SeqCst), 1);
+        run(&f, "cats sleep").await;
+        assert_eq!(node.executed.load(Ordering::SeqCst), 2);
+
+        // an expired output is executed again
+        let cache = NodeCache::memory();
+        let mut f = flow!(start: ("summary", node.clone())).with_cache(cache.clone());
+        let policy = CachePolicy::new(&["text"]);
+        f.set_cache("summary", policy.clone().with_ttl(Duration::ZERO));
+        run(&f, "robots dream").await;
+        run(&f, "robots dream").await;
+        assert_eq!(node.executed.load(Ordering::SeqCst), 4);
+
+        // a hit applies the cached writes
+        f.set_cache("summary", policy.clone());
+        let mut context = Context::new();
+        context.set("text", Value::from("robots dream"));
+        let mut cached = CachedOutput {
+            key: policy.key("summary", node.as_ref(), &context),
+            node: "summary".to_owned(),
+            output: Value::from("cached"),
+            writes: HashMap::from([("summary".to_owned(), Value::from("cached"))]),
+            messages: vec![],
+            expires_at: None,
+        };
+        cache.put(&cached).await.unwrap();
+        f.run_in(&mut context, "run").await.unwrap();
+        assert_eq!(context.get("summary"), Some(&Value::from("cached")));
+        assert_eq!(context.get(CONTEXT_RESULT), Some(&Value::from("cached")));
+        assert_eq!(node.executed.load(Ordering::SeqCst), 4);
+
+        cached.expires_at = Some(DateTime::from_millis(0));
+        let document = mongodb::bson::to_document(&cached).unwrap();
+        let restored: CachedOutput = mongodb::bson::from_document(document).unwrap();
+        assert!(restored.is_expired());
+    }
+
+    /// a different node under the same name, with the same `cache_config`
+    struct TitleNode {}
+    #[async_trait::async_trait]
+    impl Node for TitleNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            Ok(Value::from(
+                context.get_as::<String>("text")?.to_uppercase(),
+            ))
+        }
+
+        fn cache_config(&self) -> Value {
+            Value::from("first word")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_node_cache_key() {
+        let node = Arc::new(SummaryNode {
+            executed: AtomicUsize::new(0),
+        });
+        let cache = NodeCache::memory();
+        let mut summary = flow!(start: ("summary", node.clone())).with_cache(cache.clone());
+        summary.set_cache("summary", CachePolicy::new(&["text"]));
+        let mut title = flow!(start: ("summary", Arc::new(TitleNode {}))).with_cache(cache.clone());
+        title.set_cache("summary", CachePolicy::new(&["text"]));
+
+        assert_eq!(run(&summary, "robots dream").await.0, Value::from("robots"));
+        assert_eq!(
+            run(&title, "robots dream").await.0,
+            Value::from("ROBOTS DREAM")
+        );
+        assert_eq!(run(&summary, "robots dream").await.0, Value::from("robots"));
+        assert_eq!(node.executed.load(Ordering::SeqCst), 1);
+
+        // the same node in another namespace
+        let mut other = flow!(start: ("summary", node.clone())).with_cache(cache);
+        other.set_cache(
+            "summary",
+            CachePolicy::new(&["text"]).with_namespace("other"),
+        );
+        run(&other, "robots dream").await;
+        assert_eq!(node.executed.load(Ordering::SeqCst), 2);
+
+        let key = CachePolicy::new(&[]).key("summary", node.as_ref(), &Context::new());
+        assert_eq!(key.len(), 64);
+    }
+
+    fn output(key: &str, expires_at: Option<DateTime>) -> CachedOutput {
+        CachedOutput {
+            key: key.to_owned(),
+            node: "summary".to_owned(),
+            output: Value::from(key),
+            writes: HashMap::new(),
+            messages: vec![],
+            expires_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_node_cache_eviction() {
+        let cache = NodeCache::memory_with_capacity(2);
+        let NodeCache::Memory(outputs) = &cache else {
+            unreachable!()
+        };
+        cache.put(&output("a", None)).await.unwrap();
+        cache.put(&output("b", None)).await.unwrap();
+        // put again, `a` is newer than `b` now
+        cache.put(&output("a", None)).await.unwrap();
+        cache.put(&output("c", None)).await.unwrap();
+        assert_eq!(outputs.lock().unwrap().len(), 2);
+        assert!(cache.get("a").await.unwrap().is_some());
+        assert!(cache.get("b").await.unwrap().is_none());
+
+        // the expired outputs go first, even if they were never read
+        let expired = Some(DateTime::from_millis(0));
+        let cache = NodeCache::memory_with_capacity(2);
+        cache.put(&output("a", None)).await.unwrap();
+        cache.put(&output("b", expired)).await.unwrap();
+        cache.put(&output("c", None)).await.unwrap();
+        assert!(cache.get("a").await.unwrap().is_some());
+        assert!(cache.get("c").await.unwrap().is_some());
+
+        // a ttl too long for a date does not overflow
+        let node = Arc::new(SummaryNode {
+            executed: AtomicUsize::new(0),
+        });
+        let mut f = flow!(start: ("summary", node.clone())).with_cache(cache.clone());
+        f.set_cache(
+            "summary",
+            CachePolicy::new(&["text"]).with_ttl(Duration::MAX),
+        );
+        run(&f, "robots dream").await;
+        run(&f, "robots dream").await;
+        assert_eq!(node.executed.load(Ordering::SeqCst), 1);
+    }
+}
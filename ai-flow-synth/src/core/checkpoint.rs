@@ -0,0 +1,131 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use mongodb::bson::{DateTime, doc};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::MongoClient;
+
+use super::{context::ContextSnapshot, report::NodeRun};
+
+pub static CHECKPOINT_COLLECTION: &str = "flow_checkpoints";
+
+/// The state of a flow run after its last finished node, enough to resume it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowCheckpoint {
+    /// the id of the context the flow runs on
+    pub run_id: String,
+    /// the node (or fan-out) to run next, `None` once the run is finished
+    pub next_node: Option<String>,
+    pub context: ContextSnapshot,
+    /// the nodes executed so far
+    pub nodes: Vec<NodeRun>,
+    pub updated_at: DateTime,
+}
+
+/// Keeps one checkpoint per run, the latest one replaces the previous.
+/// The flow removes it once the run is finished.
+#[derive(Debug, Clone)]
+pub enum CheckpointStore {
+    Memory(Arc<Mutex<HashMap<String, FlowCheckpoint>>>),
+    Mongo(mongodb::Collection<FlowCheckpoint>),
+}
+
+impl CheckpointStore {
+    pub fn new(client: &MongoClient) -> Self {
+        CheckpointStore::Mongo(client.collection(CHECKPOINT_COLLECTION))
+    }
+
+    /// checkpoints that are lost with the process, e.g. for tests
+    pub fn memory() -> Self {
+        CheckpointStore::Memory(Arc::default())
+    }
+
+    pub async fn save(&self, checkpoint: &FlowCheckpoint) -> anyhow::Result<()> {
+        match self {
+            CheckpointStore::Memory(checkpoints) => {
+                checkpoints
+                    .lock()
+                    .unwrap()
+                    .insert(checkpoint.run_id.clone(), checkpoint.clone());
+            }
+            CheckpointStore::Mongo(collection) => {
+                collection
+                    .replace_one(doc! { "run_id": &checkpoint.run_id }, checkpoint)
+                    .upsert(true)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn load(&self, run_id: &str) -> anyhow::Result<Option<FlowCheckpoint>> {
+        match self {
+            CheckpointStore::Memory(checkpoints) => {
+                Ok(checkpoints.lock().unwrap().get(run_id).cloned())
+            }
+            CheckpointStore::Mongo(collection) => {
+                Ok(collection.find_one(doc! { "run_id": run_id }).await?)
+            }
+        }
+    }
+
+    pub async fn remove(&self, run_id: &str) -> anyhow::Result<()> {
+        match self {
+            CheckpointStore::Memory(checkpoints) => {
+                checkpoints.lock().unwrap().remove(run_id);
+            }
+            CheckpointStore::Mongo(collection) => {
+                collection.delete_one(doc! { "run_id": run_id }).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_bson() {
+        let checkpoint = FlowCheckpoint {
+            run_id: "run".to_owned(),
+            next_node: Some("editor".to_owned()),
+            context: ContextSnapshot {
+                id: "run".to_owned(),
+                data: HashMap::from([
+                    ("draft".to_owned(), Value::from("a story")),
+                    (
+                        "meta".to_owned(),
+                        serde_json::json!({ "words": 100, "tags": ["robot"] }),
+                    ),
+                ]),
+            },
+            nodes: vec![NodeRun {
+                node: "start".to_owned(),
+                fan_out: None,
+                started_at: chrono::Utc::now(),
+                finished_at: chrono::Utc::now(),
+                status: Some("Written".to_owned()),
+                failed: false,
+                message: "done".to_owned(),
+                error: None,
+                retried: Vec::new(),
+                token_usage: 12,
+            }],
+            updated_at: DateTime::now(),
+        };
+        let document = mongodb::bson::to_document(&checkpoint).unwrap();
+        let restored: FlowCheckpoint = mongodb::bson::from_document(document).unwrap();
+        assert_eq!(restored.next_node, checkpoint.next_node);
+        assert_eq!(restored.context.data, checkpoint.context.data);
+        assert_eq!(restored.nodes[0].status.as_deref(), Some("Written"));
+        assert_eq!(restored.nodes[0].token_usage, 12);
+    }
+}
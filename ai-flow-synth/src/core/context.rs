@@ -1,31 +1,96 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, marker::PhantomData, sync::Arc};
 
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::Value;
-use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 
-use super::stream_message::StreamMessage;
+use crate::llm::tool::ToolRegistry;
+
+use super::{
+    breakpoint::PausedNode,
+    error::ContextError,
+    event_log::{EventLog, EventSink, MessageStream},
+    flow::FlowBudget,
+    stream_message::StreamSender,
+};
 
 #[derive(Debug, Clone)]
 pub struct Context {
     /// uuid for one context
-    _id: String,
+    id: String,
     /// context data, the nodes can set and get the data to communicate with each other
     data: HashMap<String, Value>,
-    /// stream for the context, the nodes can send messages to the stream
-    stream: broadcast::Sender<StreamMessage>, //? consider using a generic type here
+    /// the log the nodes send their messages to, shared by all clones and forks
+    events: Arc<EventSink>,
+    /// where the flow sends the nodes it paused at, see `Flow::add_breakpoint`
+    breakpoints: Option<mpsc::Sender<PausedNode>>,
+    /// llm tokens used on this context, for the run report
+    token_usage: i64,
+    /// cancels the flow running on this context, shared by its clones
+    cancel: CancellationToken,
+    /// the node the flow is running, see [`Context::local`]
+    node: Option<String>,
+    /// what is left of the budget of the run, a sub-flow run by the node stays within it
+    budget: Option<FlowBudget>,
+    /// the tools the nodes can give their llm, shared by all clones and forks
+    tools: Arc<ToolRegistry>,
+}
+
+/// The serializable part of a [`Context`], without its stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContextSnapshot {
+    pub id: String,
+    pub data: HashMap<String, Value>,
+}
+
+/// A context key with the type of its value, declare it once and share it between nodes:
+/// `const DRAFT: ContextKey<String> = ContextKey::new("draft");`
+#[derive(Debug)]
+pub struct ContextKey<T> {
+    name: &'static str,
+    _type: PhantomData<fn() -> T>,
 }
 
+impl<T> ContextKey<T> {
+    pub const fn new(name: &'static str) -> Self {
+        ContextKey {
+            name,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T> Clone for ContextKey<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ContextKey<T> {}
+
 // maybe we should define some reserved keys for the context
 pub static CONTEXT_RESULT: &str = "result";
-// pub static CONTEXT_ERROR: &str = "error";
+/// the message of the last node error, for the error edges and the fallback
+pub static CONTEXT_ERROR: &str = "error";
 
 impl Default for Context {
     fn default() -> Self {
-        let (tx, _rx) = broadcast::channel(100);
         Context {
-            _id: uuid::Uuid::new_v4().to_string(),
+            id: uuid::Uuid::new_v4().to_string(),
             data: HashMap::new(),
-            stream: tx,
+            events: Arc::new(EventSink::default()),
+            breakpoints: None,
+            token_usage: 0,
+            cancel: CancellationToken::new(),
+            node: None,
+            budget: None,
+            tools: Arc::default(),
         }
     }
 }
@@ -35,6 +100,39 @@ impl Context {
         Context::default()
     }
 
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn snapshot(&self) -> ContextSnapshot {
+        ContextSnapshot {
+            id: self.id.clone(),
+            data: self.data.clone(),
+        }
+    }
+
+    /// Replace the id and data with the snapshot, the stream is kept.
+    pub fn restore(&mut self, snapshot: ContextSnapshot) {
+        self.id = snapshot.id;
+        self.data = snapshot.data;
+    }
+
+    /// A new context with empty data that sends to the same stream,
+    /// it is cancelled together with this one.
+    pub fn fork(&self) -> Self {
+        Context {
+            id: uuid::Uuid::new_v4().to_string(),
+            data: HashMap::new(),
+            events: self.events.clone(),
+            breakpoints: self.breakpoints.clone(),
+            token_usage: 0,
+            cancel: self.cancel.child_token(),
+            node: None,
+            budget: self.budget.clone(),
+            tools: self.tools.clone(),
+        }
+    }
+
     pub fn set(&mut self, key: &str, value: Value) {
         self.data.insert(key.to_owned(), value);
     }
@@ -47,12 +145,328 @@ impl Context {
         self.data.remove(key);
     }
 
-    pub fn stream(&mut self, _stream_id: &str) -> broadcast::Sender<StreamMessage> {
-        self.stream.clone()
+    /// The value of `key` deserialized as `T`, fails if it is missing or has another shape.
+    pub fn get_as<T: DeserializeOwned>(&self, key: &str) -> Result<T, ContextError> {
+        self.get_opt_as(key)?
+            .ok_or_else(|| ContextError::Missing(key.to_owned()))
+    }
+
+    /// Like [`Context::get_as`], a missing (or null) value is `None`.
+    pub fn get_opt_as<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, ContextError> {
+        match self.data.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => {
+                T::deserialize(value)
+                    .map(Some)
+                    .map_err(|source| ContextError::WrongShape {
+                        key: key.to_owned(),
+                        expected: std::any::type_name::<T>(),
+                        source,
+                    })
+            }
+        }
+    }
+
+    pub fn set_as<T: Serialize + ?Sized>(
+        &mut self,
+        key: &str,
+        value: &T,
+    ) -> Result<(), ContextError> {
+        let value = serde_json::to_value(value).map_err(|source| ContextError::Serialize {
+            key: key.to_owned(),
+            source,
+        })?;
+        self.set(key, value);
+        Ok(())
+    }
+
+    pub fn get_key<T: DeserializeOwned>(&self, key: &ContextKey<T>) -> Result<T, ContextError> {
+        self.get_as(key.name)
+    }
+
+    pub fn set_key<T: Serialize>(
+        &mut self,
+        key: &ContextKey<T>,
+        value: &T,
+    ) -> Result<(), ContextError> {
+        self.set_as(key.name, value)
+    }
+
+    /// The keys under `namespace`, `scope.set("draft", ..)` sets `"{namespace}.draft"`,
+    /// the same keys `MergeStrategy::Namespaced` writes.
+    pub fn namespace(&mut self, namespace: &str) -> ContextScope<'_> {
+        ContextScope {
+            prefix: format!("{namespace}."),
+            context: self,
+        }
+    }
+
+    /// The namespace of the node the flow is running, the root keys outside of a flow.
+    pub fn local(&mut self) -> ContextScope<'_> {
+        let prefix = self
+            .node
+            .as_ref()
+            .map(|node| format!("{node}."))
+            .unwrap_or_default();
+        ContextScope {
+            prefix,
+            context: self,
+        }
+    }
+
+    /// the name of the node the flow is running
+    pub fn node(&self) -> Option<&str> {
+        self.node.as_deref()
+    }
+
+    pub(crate) fn set_node(&mut self, node: Option<String>) {
+        self.node = node;
+    }
+
+    pub(crate) fn budget(&self) -> Option<&FlowBudget> {
+        self.budget.as_ref()
+    }
+
+    pub(crate) fn set_budget(&mut self, budget: Option<FlowBudget>) {
+        self.budget = budget;
+    }
+
+    pub(crate) fn data(&self) -> &HashMap<String, Value> {
+        &self.data
+    }
+
+    /// the keys set or changed since the `before` snapshot of [`Context::data`]
+    pub(crate) fn writes_since(&self, before: &HashMap<String, Value>) -> HashMap<String, Value> {
+        self.data
+            .iter()
+            .filter(|(key, value)| before.get(*key) != Some(*value))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Count the llm tokens a node used, see [`crate::llm::chat_with_usage`].
+    pub fn add_token_usage(&mut self, tokens: i64) {
+        self.token_usage += tokens;
+    }
+
+    pub fn token_usage(&self) -> i64 {
+        self.token_usage
+    }
+
+    /// Stop the flow running on this context (or a clone of it), the run ends with
+    /// `FlowError::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
     }
 
-    pub fn listen(&self) -> tokio_stream::wrappers::BroadcastStream<StreamMessage> {
-        let receiver = self.stream.subscribe();
-        tokio_stream::wrappers::BroadcastStream::new(receiver)
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Pass it to long running calls like `llm::chat`, or keep a clone to cancel the run
+    /// after the context is moved into it.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    /// A sender into the stream `stream_id`, its messages are tagged with the current node.
+    pub fn stream(&self, stream_id: &str) -> StreamSender {
+        StreamSender::new(self.events.clone(), stream_id, self.node.clone())
+    }
+
+    /// Receive the flow's breakpoint pauses, the run waits until each one is resumed.
+    /// Call it before cloning the context into the run.
+    pub fn enable_breakpoints(&mut self) -> mpsc::Receiver<PausedNode> {
+        let (tx, rx) = mpsc::channel(1);
+        self.breakpoints = Some(tx);
+        rx
+    }
+
+    /// Give the nodes `tools`, see [`Context::tools`].
+    pub fn set_tools(&mut self, tools: Arc<ToolRegistry>) {
+        self.tools = tools;
+    }
+
+    /// the tools of the run, empty unless set, an agent run has the agent's tools
+    pub fn tools(&self) -> &Arc<ToolRegistry> {
+        &self.tools
+    }
+
+    pub(crate) fn breakpoints(&self) -> Option<&mpsc::Sender<PausedNode>> {
+        self.breakpoints.as_ref()
+    }
+
+    /// Every message sent on this context so far, and the ones to come.
+    pub fn event_log(&self) -> EventLog {
+        self.events.log()
+    }
+
+    /// The messages of all streams and nodes sent from now on,
+    /// the stream ends once every clone of the context is dropped.
+    pub fn listen(&self) -> MessageStream {
+        self.event_log().listen()
+    }
+
+    /// The messages from `seq` on, the ones sent before are replayed first.
+    /// `listen_from(0)` sees the whole run, however late it is called.
+    pub fn listen_from(&self, seq: u64) -> MessageStream {
+        self.event_log().listen_from(seq)
+    }
+
+    /// The messages sent while the flow ran the node `node`.
+    pub fn listen_node(&self, node: &str) -> MessageStream {
+        let node = node.to_owned();
+        Box::pin(
+            self.listen()
+                .filter(move |message| message.node.as_ref() == Some(&node)),
+        )
+    }
+
+    /// The messages sent into the stream `stream_id`.
+    pub fn listen_stream(&self, stream_id: &str) -> MessageStream {
+        let stream_id = stream_id.to_owned();
+        Box::pin(
+            self.listen()
+                .filter(move |message| message.stream == stream_id),
+        )
+    }
+}
+
+/// The keys of a [`Context`] under one namespace, see [`Context::namespace`].
+pub struct ContextScope<'a> {
+    context: &'a mut Context,
+    prefix: String,
+}
+
+impl ContextScope<'_> {
+    /// the full context key of `key`
+    pub fn key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.context.get(&self.key(key))
+    }
+
+    pub fn set(&mut self, key: &str, value: Value) {
+        let key = self.key(key);
+        self.context.set(&key, value);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        let key = self.key(key);
+        self.context.remove(&key);
+    }
+
+    pub fn get_as<T: DeserializeOwned>(&self, key: &str) -> Result<T, ContextError> {
+        self.context.get_as(&self.key(key))
+    }
+
+    pub fn get_opt_as<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, ContextError> {
+        self.context.get_opt_as(&self.key(key))
+    }
+
+    pub fn set_as<T: Serialize + ?Sized>(
+        &mut self,
+        key: &str,
+        value: &T,
+    ) -> Result<(), ContextError> {
+        let key = self.key(key);
+        self.context.set_as(&key, value)
+    }
+
+    pub fn get_key<T: DeserializeOwned>(&self, key: &ContextKey<T>) -> Result<T, ContextError> {
+        self.get_as(key.name)
+    }
+
+    pub fn set_key<T: Serialize>(
+        &mut self,
+        key: &ContextKey<T>,
+        value: &T,
+    ) -> Result<(), ContextError> {
+        self.set_as(key.name, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::core::stream_message::StreamMessage;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Draft {
+        title: String,
+        words: usize,
+    }
+
+    const DRAFT: ContextKey<Draft> = ContextKey::new("draft");
+
+    #[test]
+    fn test_context_typed() {
+        let mut context = Context::new();
+        let draft = Draft {
+            title: "robots".to_owned(),
+            words: 100,
+        };
+        context.set_key(&DRAFT, &draft).unwrap();
+        assert_eq!(context.get_key(&DRAFT).unwrap(), draft);
+        assert_eq!(context.get_as::<usize>("draft").ok(), None);
+        assert!(matches!(
+            context.get_as::<String>("missing"),
+            Err(ContextError::Missing(key)) if key == "missing"
+        ));
+        assert!(matches!(
+            context.get_as::<String>("draft"),
+            Err(ContextError::WrongShape { .. })
+        ));
+        assert_eq!(context.get_opt_as::<String>("missing").unwrap(), None);
+
+        context.set_node(Some("writer".to_owned()));
+        context.local().set_as("draft", "a story").unwrap();
+        assert_eq!(context.get("writer.draft"), Some(&Value::from("a story")));
+        assert_eq!(
+            context
+                .namespace("writer")
+                .get_as::<String>("draft")
+                .unwrap(),
+            "a story"
+        );
+        assert_eq!(context.get_key(&DRAFT).unwrap().words, 100);
+    }
+
+    #[tokio::test]
+    async fn test_context_streams() {
+        let mut context = Context::new();
+        let mut all = context.listen();
+        let mut editor = context.listen_node("editor");
+
+        context.set_node(Some("writer".to_owned()));
+        let writer_stream = context.stream("draft");
+        context.set_node(Some("editor".to_owned()));
+        let editor_stream = context.stream("review");
+        writer_stream.send(StreamMessage::Delta("once".to_owned()));
+        editor_stream.send(StreamMessage::Delta("Once".to_owned()));
+
+        let first = all.next().await.unwrap();
+        assert_eq!(
+            (first.stream.as_str(), first.node.as_deref(), first.seq),
+            ("draft", Some("writer"), 0)
+        );
+        let second = editor.next().await.unwrap();
+        assert_eq!(
+            (second.stream.as_str(), second.node.as_deref(), second.seq),
+            ("review", Some("editor"), 1)
+        );
+
+        // a late listener replays the run, and is done once the context is gone
+        let late = context.listen_from(1);
+        drop((context, writer_stream, editor_stream));
+        assert_eq!(
+            late.map(|message| message.message)
+                .collect::<Vec<_>>()
+                .await,
+            vec![StreamMessage::Delta("Once".to_owned())]
+        );
     }
 }
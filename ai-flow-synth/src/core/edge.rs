@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use super::{context::Context, node::NodeResult, status::Status};
+
+pub type EdgePredicate<S> = Arc<dyn Fn(&NodeResult<S>, &Context) -> bool + Send + Sync>;
+
+/// When an edge is taken, see [`Edge`]
+pub enum EdgeCondition<S: Status> {
+    /// the node returned this status
+    Status(S),
+    /// the predicate returns true, `label` names it in graphs and problems
+    When {
+        label: String,
+        predicate: EdgePredicate<S>,
+    },
+    /// the node failed with an error, see [`NodeResult::error`]
+    Error,
+    /// no other edge of the node matched
+    Default,
+}
+
+/// An edge from a node to `to`. The edges of a node are tried by descending `priority`,
+/// then in the order they were added; an error edge is tried before all others when the node
+/// failed with an error, the default edge only after all others.
+pub struct Edge<S: Status> {
+    pub condition: EdgeCondition<S>,
+    pub to: String,
+    pub priority: i32,
+}
+
+impl<S: Status> Edge<S> {
+    pub fn status(status: S, to: &str) -> Self {
+        Edge::new(EdgeCondition::Status(status), to)
+    }
+
+    /// `flow.insert_edge("writer", Edge::when("too long", "shorten", |_, ctx| ...))`
+    pub fn when(
+        label: &str,
+        to: &str,
+        predicate: impl Fn(&NodeResult<S>, &Context) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Edge::new(
+            EdgeCondition::When {
+                label: label.to_owned(),
+                predicate: Arc::new(predicate),
+            },
+            to,
+        )
+    }
+
+    /// taken if the node failed with an error, before the edges of the failed status
+    pub fn on_error(to: &str) -> Self {
+        Edge::new(EdgeCondition::Error, to)
+    }
+
+    /// taken if no other edge matches, also for the failed status
+    pub fn otherwise(to: &str) -> Self {
+        Edge::new(EdgeCondition::Default, to)
+    }
+
+    fn new(condition: EdgeCondition<S>, to: &str) -> Self {
+        Edge {
+            condition,
+            to: to.to_owned(),
+            priority: 0,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn is_default(&self) -> bool {
+        matches!(self.condition, EdgeCondition::Default)
+    }
+
+    pub fn is_on_error(&self) -> bool {
+        matches!(self.condition, EdgeCondition::Error)
+    }
+
+    /// the default edge never matches here, it is the fallback of [`select_edge`]
+    pub fn matches(&self, result: &NodeResult<S>, context: &Context) -> bool {
+        match &self.condition {
+            EdgeCondition::Status(status) => result.status == *status,
+            EdgeCondition::When { predicate, .. } => predicate(result, context),
+            EdgeCondition::Error => result.error.is_some(),
+            EdgeCondition::Default => false,
+        }
+    }
+
+    /// `Status::label` of the status (`status` if it has none), the label of a predicate,
+    /// `error` for the error edge and `else` for the default edge
+    pub fn label(&self) -> String {
+        match &self.condition {
+            EdgeCondition::Status(status) => status.label().unwrap_or("status").to_owned(),
+            EdgeCondition::When { label, .. } => label.clone(),
+            EdgeCondition::Error => "error".to_owned(),
+            EdgeCondition::Default => "else".to_owned(),
+        }
+    }
+}
+
+/// Insert `edge` after the edges with the same or a higher priority.
+pub(crate) fn insert_edge<S: Status>(edges: &mut Vec<Edge<S>>, edge: Edge<S>) {
+    let index = edges.partition_point(|e| e.priority >= edge.priority);
+    edges.insert(index, edge);
+}
+
+/// The error edge if the node failed with an error, the first matching edge,
+/// else the default one.
+pub(crate) fn select_edge<'a, S: Status>(
+    edges: &'a [Edge<S>],
+    result: &NodeResult<S>,
+    context: &Context,
+) -> Option<&'a Edge<S>> {
+    let on_error = result
+        .error
+        .as_ref()
+        .and_then(|_| edges.iter().find(|e| e.is_on_error()));
+    on_error
+        .or_else(|| edges.iter().find(|edge| edge.matches(result, context)))
+        .or_else(|| edges.iter().find(|edge| edge.is_default()))
+}
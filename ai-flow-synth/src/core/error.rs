@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+#[derive(thiserror::Error, Debug)]
+pub enum FlowError {
+    #[error("FlowError BudgetExceeded: {0}")]
+    BudgetExceeded(BudgetExceeded),
+
+    #[error(
+        "FlowError FanOutFailed: '{fan_out}' needs {required} branches, only {succeeded} succeeded"
+    )]
+    FanOutFailed {
+        fan_out: String,
+        succeeded: usize,
+        required: usize,
+    },
+
+    #[error("FlowError MergeConflict: '{fan_out}' branches {branches:?} both wrote '{key}'")]
+    MergeConflict {
+        fan_out: String,
+        key: String,
+        branches: (String, String),
+    },
+
+    #[error("FlowError Checkpoint: {0}")]
+    Checkpoint(String),
+
+    #[error("FlowError Template: {0}")]
+    Template(String),
+
+    #[error("FlowError Agent: {0}")]
+    Agent(String),
+
+    #[error("FlowError Retry: {0}")]
+    Retry(String),
+
+    #[error("FlowError Invalid: {}", display_problems(.0))]
+    Invalid(Vec<FlowProblem>),
+
+    #[error("FlowError Cancelled")]
+    Cancelled,
+
+    #[error("FlowError Timeout: execute took more than {0:?}")]
+    Timeout(Duration),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ContextError {
+    #[error("ContextError Missing: no value for '{0}'")]
+    Missing(String),
+
+    #[error("ContextError WrongShape: '{key}' is not a {expected}: {source}")]
+    WrongShape {
+        key: String,
+        expected: &'static str,
+        source: serde_json::Error,
+    },
+
+    #[error("ContextError Serialize: '{key}': {source}")]
+    Serialize {
+        key: String,
+        source: serde_json::Error,
+    },
+}
+
+/// Which part of the [`super::flow::FlowBudget`] the run ran out of
+#[derive(Debug, Clone, PartialEq)]
+pub enum BudgetExceeded {
+    MaxSteps(usize),
+    MaxVisits { node: String, limit: usize },
+    Deadline(Duration),
+}
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BudgetExceeded::MaxSteps(limit) => write!(f, "more than {limit} steps"),
+            BudgetExceeded::MaxVisits { node, limit } => {
+                write!(f, "node '{node}' visited more than {limit} times")
+            }
+            BudgetExceeded::Deadline(deadline) => write!(f, "deadline {deadline:?} reached"),
+        }
+    }
+}
+
+/// A problem found by `Flow::validate`
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlowProblem {
+    /// an edge from or to a name that is neither a node nor a fan-out
+    DanglingEdge { from: String, to: String },
+    /// a fan-out branch or join that is not a node
+    DanglingFanOut { fan_out: String, node: String },
+    /// a fan-out with the same name as a node, the node is never run
+    NameConflict { name: String },
+    /// the node can not be reached from the start node
+    Unreachable { node: String },
+    /// a second edge with the same condition from one node, it never matches
+    DuplicateCondition { from: String, condition: String },
+    /// the node has edges but none for the failed status, a failure silently ends the run
+    NoFailureExit { node: String },
+    /// the fallback of the flow is not a node
+    DanglingFallback { node: String },
+}
+
+impl FlowProblem {
+    /// errors break the run, the others are likely mistakes
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            FlowProblem::DanglingEdge { .. }
+                | FlowProblem::DanglingFanOut { .. }
+                | FlowProblem::NameConflict { .. }
+                | FlowProblem::DanglingFallback { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for FlowProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlowProblem::DanglingEdge { from, to } => {
+                write!(f, "edge '{from}' -> '{to}' is dangling")
+            }
+            FlowProblem::DanglingFanOut { fan_out, node } => {
+                write!(f, "fan-out '{fan_out}' uses undefined node '{node}'")
+            }
+            FlowProblem::NameConflict { name } => {
+                write!(f, "'{name}' is both a node and a fan-out")
+            }
+            FlowProblem::Unreachable { node } => write!(f, "node '{node}' is unreachable"),
+            FlowProblem::DuplicateCondition { from, condition } => {
+                write!(f, "node '{from}' has more than one edge for {condition}")
+            }
+            FlowProblem::NoFailureExit { node } => {
+                write!(f, "node '{node}' has no edge for the failed status")
+            }
+            FlowProblem::DanglingFallback { node } => {
+                write!(f, "fallback '{node}' is not a node")
+            }
+        }
+    }
+}
+
+fn display_problems(problems: &[FlowProblem]) -> String {
+    problems
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
@@ -0,0 +1,150 @@
+use std::sync::{Arc, Mutex};
+
+use futures::stream::BoxStream;
+use tokio::sync::watch;
+
+use super::stream_message::{StreamMessage, TaggedMessage};
+
+pub type MessageStream = BoxStream<'static, TaggedMessage>;
+
+/// Appends the messages of one context, shared by its clones and forks.
+/// The streams of the listeners end once it is dropped.
+#[derive(Debug)]
+pub(crate) struct EventSink {
+    events: Arc<Mutex<Vec<TaggedMessage>>>,
+    /// the number of events, listeners wait for it to change
+    appended: watch::Sender<u64>,
+}
+
+impl Default for EventSink {
+    fn default() -> Self {
+        let (appended, _) = watch::channel(0);
+        EventSink {
+            events: Arc::new(Mutex::new(Vec::new())),
+            appended,
+        }
+    }
+}
+
+impl EventSink {
+    /// append the message, the seq is its index in the log
+    pub(crate) fn append(&self, stream: &str, node: Option<String>, message: StreamMessage) -> u64 {
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        let seq = events.len() as u64;
+        events.push(TaggedMessage {
+            stream: stream.to_owned(),
+            node,
+            seq,
+            message,
+        });
+        // still locked, the count never goes back for the listeners
+        self.appended.send_replace(seq + 1);
+        seq
+    }
+
+    pub(crate) fn log(&self) -> EventLog {
+        EventLog {
+            events: self.events.clone(),
+            appended: self.appended.subscribe(),
+        }
+    }
+}
+
+/// The append-only log of every message sent on a context, get it from `Context::event_log`.
+///
+/// A listener replays the log from any seq, then follows the new messages,
+/// so it neither misses the start of a run nor falls behind a fast node.
+/// Keeping it does not keep the run's streams open.
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    events: Arc<Mutex<Vec<TaggedMessage>>>,
+    appended: watch::Receiver<u64>,
+}
+
+impl EventLog {
+    /// the seq the next message gets
+    pub fn len(&self) -> u64 {
+        self.lock().len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the messages from `seq` on that were sent so far
+    pub fn since(&self, seq: u64) -> Vec<TaggedMessage> {
+        let events = self.lock();
+        events
+            .get(seq as usize..)
+            .map(<[_]>::to_vec)
+            .unwrap_or_default()
+    }
+
+    /// The messages from `seq` on, ends once every context of the run is dropped.
+    /// A client that reconnects passes the seq after the last one it received.
+    pub fn listen_from(&self, seq: u64) -> MessageStream {
+        let log = self.clone();
+        Box::pin(async_stream::stream! {
+            let mut appended = log.appended.clone();
+            let mut next = seq;
+            loop {
+                for message in log.since(next) {
+                    next = message.seq + 1;
+                    yield message;
+                }
+                if appended.changed().await.is_err() {
+                    // the run is gone, send what it appended last
+                    for message in log.since(next) {
+                        yield message;
+                    }
+                    break;
+                }
+            }
+        })
+    }
+
+    /// the messages sent from now on
+    pub fn listen(&self) -> MessageStream {
+        self.listen_from(self.len())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<TaggedMessage>> {
+        self.events.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    fn delta(s: &str) -> StreamMessage {
+        StreamMessage::Delta(s.to_owned())
+    }
+
+    #[tokio::test]
+    async fn test_event_log_replay() {
+        let sink = EventSink::default();
+        let log = sink.log();
+        assert_eq!(sink.append("draft", None, delta("once")), 0);
+        assert_eq!(sink.append("draft", None, delta(" upon")), 1);
+
+        let mut late = log.listen_from(1);
+        let mut live = log.listen();
+        sink.append("draft", None, delta(" a time"));
+        assert_eq!(late.next().await.unwrap().message, delta(" upon"));
+        assert_eq!(late.next().await.unwrap().message, delta(" a time"));
+        assert_eq!(live.next().await.unwrap().seq, 2);
+
+        // far more than a broadcast channel holds, a slow listener still gets all of them
+        for i in 0..1000 {
+            sink.append("draft", None, delta(&i.to_string()));
+        }
+        drop(sink);
+        assert_eq!(late.collect::<Vec<_>>().await.len(), 1000);
+        assert_eq!(log.listen_from(0).collect::<Vec<_>>().await.len(), 1003);
+        assert_eq!(log.since(1002)[0].message, delta("999"));
+    }
+}
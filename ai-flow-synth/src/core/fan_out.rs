@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{
+    context::{CONTEXT_ERROR, CONTEXT_RESULT, Context},
+    error::FlowError,
+};
+
+/// Run several nodes concurrently on copies of the same context,
+/// then merge their writes back and continue at the join node. A fan-out that fails
+/// is routed like a failed node, by its error edges or the fallback of the flow.
+#[derive(Debug, Clone)]
+pub struct FanOut {
+    pub(crate) branches: Vec<String>,
+    pub(crate) join: String,
+    pub(crate) policy: JoinPolicy,
+    pub(crate) merge: MergeStrategy,
+}
+
+/// When the fan-out is done waiting for its branches
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum JoinPolicy {
+    /// every branch has to succeed
+    #[default]
+    All,
+    /// continue as soon as N branches succeeded, the rest are dropped
+    AtLeast(usize),
+}
+
+/// How the context writes of the succeeded branches are merged back.
+/// The reserved keys every node writes, `result` and `error`, are always merged
+/// as `<branch>.<key>`, they never conflict.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategy {
+    /// apply the writes in branch order, the later branch wins on the same key
+    #[default]
+    Overwrite,
+    /// write every key as `<branch>.<key>`, so branches never collide
+    Namespaced,
+    /// fail the fan-out if two branches write the same key
+    FailOnConflict,
+}
+
+impl FanOut {
+    pub fn new(branches: &[&str], join: &str) -> Self {
+        FanOut {
+            branches: branches.iter().map(|b| b.to_string()).collect(),
+            join: join.to_owned(),
+            policy: JoinPolicy::default(),
+            merge: MergeStrategy::default(),
+        }
+    }
+
+    pub fn at_least(mut self, n: usize) -> Self {
+        self.policy = JoinPolicy::AtLeast(n);
+        self
+    }
+
+    pub fn with_merge(mut self, merge: MergeStrategy) -> Self {
+        self.merge = merge;
+        self
+    }
+
+    pub(crate) fn required(&self) -> usize {
+        match self.policy {
+            JoinPolicy::All => self.branches.len(),
+            JoinPolicy::AtLeast(n) => n.min(self.branches.len()),
+        }
+    }
+
+    /// `writes` are the succeeded branches' writes, `(branch, writes)` in branch order
+    pub(crate) fn merge_into(
+        &self,
+        name: &str,
+        context: &mut Context,
+        writes: Vec<(String, HashMap<String, Value>)>,
+    ) -> Result<(), FlowError> {
+        if self.merge == MergeStrategy::FailOnConflict {
+            let mut written_by: HashMap<&str, &str> = HashMap::new();
+            for (branch, branch_writes) in &writes {
+                for key in branch_writes.keys().filter(|key| !is_reserved(key)) {
+                    if let Some(other) = written_by.insert(key, branch) {
+                        return Err(FlowError::MergeConflict {
+                            fan_out: name.to_owned(),
+                            key: key.clone(),
+                            branches: (other.to_owned(), branch.clone()),
+                        });
+                    }
+                }
+            }
+        }
+        for (branch, branch_writes) in writes {
+            let mut branch_writes = branch_writes.into_iter().collect::<Vec<_>>();
+            branch_writes.sort_by(|a, b| a.0.cmp(&b.0));
+            for (key, value) in branch_writes {
+                if self.merge == MergeStrategy::Namespaced || is_reserved(&key) {
+                    context.set(&format!("{branch}.{key}"), value);
+                } else {
+                    context.set(&key, value);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_reserved(key: &str) -> bool {
+    key == CONTEXT_RESULT || key == CONTEXT_ERROR
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    fn writes(branch: &str, key: &str, value: &str) -> (String, HashMap<String, Value>) {
+        (
+            branch.to_owned(),
+            HashMap::from([(key.to_owned(), Value::String(value.to_owned()))]),
+        )
+    }
+
+    #[test]
+    fn test_merge_strategy() {
+        let fan_out = FanOut::new(&["a", "b"], "join");
+        let mut context = Context::new();
+        let all = vec![writes("a", "draft", "1"), writes("b", "draft", "2")];
+        fan_out.merge_into("f", &mut context, all.clone()).unwrap();
+        assert_eq!(context.get("draft"), Some(&Value::String("2".to_owned())));
+
+        let fan_out = fan_out.with_merge(MergeStrategy::Namespaced);
+        let mut context = Context::new();
+        fan_out.merge_into("f", &mut context, all.clone()).unwrap();
+        assert_eq!(context.get("a.draft"), Some(&Value::String("1".to_owned())));
+        assert_eq!(context.get("b.draft"), Some(&Value::String("2".to_owned())));
+
+        let fan_out = fan_out.with_merge(MergeStrategy::FailOnConflict);
+        let mut context = Context::new();
+        let err = fan_out.merge_into("f", &mut context, all).unwrap_err();
+        assert!(matches!(err, FlowError::MergeConflict { .. }));
+        assert!(context.get("draft").is_none());
+    }
+
+    #[test]
+    fn test_merge_reserved_keys() {
+        let all = vec![writes("a", "result", "1"), writes("b", "result", "2")];
+        for merge in [MergeStrategy::Overwrite, MergeStrategy::FailOnConflict] {
+            let fan_out = FanOut::new(&["a", "b"], "join").with_merge(merge);
+            let mut context = Context::new();
+            fan_out.merge_into("f", &mut context, all.clone()).unwrap();
+            assert_eq!(
+                context.get("a.result"),
+                Some(&Value::String("1".to_owned()))
+            );
+            assert_eq!(
+                context.get("b.result"),
+                Some(&Value::String("2".to_owned()))
+            );
+            assert!(context.get(CONTEXT_RESULT).is_none());
+        }
+    }
+}
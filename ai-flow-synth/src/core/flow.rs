@@ -1,17 +1,89 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use chrono::Utc;
+use futures::{StreamExt, stream::FuturesUnordered};
 use serde_json::Value;
+use tokio_util::sync::CancellationToken;
 
 use super::{
-    context::{CONTEXT_RESULT, Context},
-    node::Node,
+    breakpoint::PausedNode,
+    cache::{CachePolicy, NodeCache},
+    checkpoint::{CheckpointStore, FlowCheckpoint},
+    context::{CONTEXT_ERROR, CONTEXT_RESULT, Context},
+    edge::{Edge, EdgeCondition, insert_edge, select_edge},
+    error::{BudgetExceeded, FlowError, FlowProblem},
+    fan_out::FanOut,
+    graph::{FlowGraph, GraphEdge, GraphFanOut},
+    node::{Node, NodeResult, NodeTrace, run_node_traced},
+    observer::FlowObserver,
+    report::{FlowRunReport, NodeRun, RunStatus},
+    retry::RetryPolicy,
     status::Status,
+    stream_message::{FLOW_STREAM, StreamMessage},
 };
 
+/// Limits for one `Flow::run`, to stop a flow whose edges loop forever.
+/// `None` means unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct FlowBudget {
+    /// max number of nodes executed in one run
+    pub max_steps: Option<usize>,
+    /// max number of times any single node can be executed in one run
+    pub max_visits: Option<usize>,
+    /// wall-clock limit of the whole run, a running node is aborted when it's reached
+    pub deadline: Option<Duration>,
+}
+
+impl FlowBudget {
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    pub fn with_max_visits(mut self, max_visits: usize) -> Self {
+        self.max_visits = Some(max_visits);
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// the tighter of both budgets, limit by limit
+    pub(crate) fn within(&self, outer: &FlowBudget) -> FlowBudget {
+        fn min<T: Ord + Copy>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        FlowBudget {
+            max_steps: min(self.max_steps, outer.max_steps),
+            max_visits: min(self.max_visits, outer.max_visits),
+            deadline: min(self.deadline, outer.deadline),
+        }
+    }
+}
+
 pub struct Flow<S: Status> {
     nodes: HashMap<String, Arc<dyn Node<FlowStatus = S>>>,
-    edges: HashMap<String, Vec<(S, String)>>, // <from, [condition, to]>
+    edges: HashMap<String, Vec<Edge<S>>>, // <from, edges by priority>
+    fan_outs: HashMap<String, FanOut>,
     start_node: String,
+    budget: FlowBudget,
+    checkpoint: Option<CheckpointStore>,
+    breakpoints: HashSet<String>,
+    retries: HashMap<String, RetryPolicy>,
+    timeouts: HashMap<String, Duration>,
+    observers: Vec<Arc<dyn FlowObserver<S>>>,
+    fallback: Option<String>,
+    cache: Option<NodeCache>,
+    cached: HashMap<String, CachePolicy>,
 }
 
 impl<S: Status> Flow<S> {
@@ -21,51 +93,878 @@ impl<S: Status> Flow<S> {
         Flow {
             nodes,
             edges: HashMap::new(),
+            fan_outs: HashMap::new(),
             start_node: start_node_name.to_owned(),
+            budget: FlowBudget::default(),
+            checkpoint: None,
+            breakpoints: HashSet::new(),
+            retries: HashMap::new(),
+            timeouts: HashMap::new(),
+            observers: Vec::new(),
+            fallback: None,
+            cache: None,
+            cached: HashMap::new(),
         }
     }
 
+    pub fn with_budget(mut self, budget: FlowBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn set_budget(&mut self, budget: FlowBudget) {
+        self.budget = budget;
+    }
+
+    /// Save a checkpoint after every node, keyed by the context id,
+    /// so the run can be continued by `resume` after a restart.
+    pub fn with_checkpoint(mut self, store: CheckpointStore) -> Self {
+        self.checkpoint = Some(store);
+        self
+    }
+
+    /// Pause the run after `node`'s `after_exec`, see [`Context::enable_breakpoints`].
+    pub fn add_breakpoint(&mut self, node: &str) {
+        self.breakpoints.insert(node.to_owned());
+    }
+
+    /// Retry `execute` of the node `node` by `policy` before `after_exec` sees the error.
+    pub fn set_retry(&mut self, node: &str, policy: RetryPolicy) {
+        self.retries.insert(node.to_owned(), policy);
+    }
+
+    /// Fail `execute` of the node `node` with `FlowError::Timeout` after `timeout`,
+    /// each retry attempt gets the full `timeout`.
+    pub fn set_timeout(&mut self, node: &str, timeout: Duration) {
+        self.timeouts.insert(node.to_owned(), timeout);
+    }
+
+    /// Add an observer around every node of the run, see [`FlowObserver`] for the order.
+    pub fn with_observer(mut self, observer: Arc<dyn FlowObserver<S>>) -> Self {
+        self.add_observer(observer);
+        self
+    }
+
+    pub fn add_observer(&mut self, observer: Arc<dyn FlowObserver<S>>) {
+        self.observers.push(observer);
+    }
+
+    /// Go to the node `node` when a node fails with an error that none of its edges routes,
+    /// the error message is in the context under [`CONTEXT_ERROR`].
+    pub fn with_fallback(mut self, node: &str) -> Self {
+        self.set_fallback(node);
+        self
+    }
+
+    pub fn set_fallback(&mut self, node: &str) {
+        self.fallback = Some(node.to_owned());
+    }
+
+    /// Keep the outputs of the nodes added by `set_cache` in `cache`.
+    pub fn with_cache(mut self, cache: NodeCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Skip `execute` of the node `node` if its output for the same reads is cached,
+    /// the cached writes and stream messages are replayed instead. Needs `with_cache`.
+    pub fn set_cache(&mut self, node: &str, policy: CachePolicy) {
+        self.cached.insert(node.to_owned(), policy);
+    }
+
     pub fn add_node(&mut self, name: &str, node: Arc<dyn Node<FlowStatus = S>>) {
         self.nodes.insert(name.to_owned(), node);
     }
 
     pub fn add_edge(&mut self, from: &str, condition: S, to: &str) {
-        self.edges
-            .entry(from.to_owned())
-            .or_default()
-            .push((condition, to.to_owned()));
-    }
-
-    pub async fn run(&self, mut context: Context) -> anyhow::Result<Value> {
-        let mut current_node_name = self.start_node.clone();
-        while let Some(node) = self.nodes.get(&current_node_name) {
-            // pre:
-            node.prepare(&mut context).await?;
-            // exec:
-            let result = node.execute(&mut context).await;
-
-            // after_exec:
-            let result = node.after_exec(&mut context, &result).await?;
-
-            if let Some(edges) = self.edges.get(&current_node_name) {
-                // find the next node based on the result
-                let mut found_next_node = false;
-                for (condition, to) in edges {
-                    if result.status == *condition {
-                        current_node_name = to.clone();
-                        found_next_node = true;
-                        break;
+        self.insert_edge(from, Edge::status(condition, to));
+    }
+
+    /// Go to `to` if `predicate` holds for the node's result and the context,
+    /// `label` names the edge in graphs.
+    pub fn add_edge_if(
+        &mut self,
+        from: &str,
+        label: &str,
+        predicate: impl Fn(&NodeResult<S>, &Context) -> bool + Send + Sync + 'static,
+        to: &str,
+    ) {
+        self.insert_edge(from, Edge::when(label, to, predicate));
+    }
+
+    /// Go to `to` if `from` fails with an error (of `prepare`, `execute` or `after_exec`),
+    /// before its edges for the failed status.
+    pub fn add_error_edge(&mut self, from: &str, to: &str) {
+        self.insert_edge(from, Edge::on_error(to));
+    }
+
+    /// Go to `to` if no other edge of `from` matches.
+    pub fn add_default_edge(&mut self, from: &str, to: &str) {
+        self.insert_edge(from, Edge::otherwise(to));
+    }
+
+    /// Add an edge built with [`Edge`], e.g. one with a priority.
+    pub fn insert_edge(&mut self, from: &str, edge: Edge<S>) {
+        insert_edge(self.edges.entry(from.to_owned()).or_default(), edge);
+    }
+
+    /// Add a fan-out named `name`, edges pointing to `name` start all of its branches.
+    pub fn add_fan_out(&mut self, name: &str, fan_out: FanOut) {
+        self.fan_outs.insert(name.to_owned(), fan_out);
+    }
+
+    /// Check the graph for dangling names, unreachable nodes, dead edges
+    /// and nodes whose failure has nowhere to go.
+    pub fn validate(&self) -> Vec<FlowProblem> {
+        let mut problems = Vec::new();
+        let exists = |name: &str| self.nodes.contains_key(name) || self.fan_outs.contains_key(name);
+
+        let mut fan_out_names = self.fan_outs.keys().collect::<Vec<_>>();
+        fan_out_names.sort();
+        for name in &fan_out_names {
+            if self.nodes.contains_key(*name) {
+                problems.push(FlowProblem::NameConflict {
+                    name: name.to_string(),
+                });
+            }
+            let fan_out = &self.fan_outs[*name];
+            for node in fan_out.branches.iter().chain([&fan_out.join]) {
+                if !self.nodes.contains_key(node) {
+                    problems.push(FlowProblem::DanglingFanOut {
+                        fan_out: name.to_string(),
+                        node: node.clone(),
+                    });
+                }
+            }
+        }
+
+        let mut from_names = self.edges.keys().collect::<Vec<_>>();
+        from_names.sort();
+        for from in from_names {
+            let edges = &self.edges[from];
+            for (index, edge) in edges.iter().enumerate() {
+                if !exists(from) || !exists(&edge.to) {
+                    problems.push(FlowProblem::DanglingEdge {
+                        from: from.clone(),
+                        to: edge.to.clone(),
+                    });
+                }
+                // predicates may differ by what they read, only equal statuses shadow
+                let shadowed = edges[..index].iter().any(|earlier| {
+                    match (&earlier.condition, &edge.condition) {
+                        (EdgeCondition::Status(a), EdgeCondition::Status(b)) => a == b,
+                        (EdgeCondition::Error, EdgeCondition::Error) => true,
+                        (EdgeCondition::Default, EdgeCondition::Default) => true,
+                        _ => false,
                     }
+                });
+                if shadowed {
+                    problems.push(FlowProblem::DuplicateCondition {
+                        from: from.clone(),
+                        condition: edge.label(),
+                    });
                 }
-                if !found_next_node {
-                    break; // no next node found, exit the loop
+            }
+            let failure_exit = edges.iter().any(|edge| match &edge.condition {
+                EdgeCondition::Status(status) => *status == S::failed(),
+                EdgeCondition::Default => true,
+                EdgeCondition::When { .. } | EdgeCondition::Error => false,
+            });
+            if self.nodes.contains_key(from) && !failure_exit {
+                problems.push(FlowProblem::NoFailureExit { node: from.clone() });
+            }
+        }
+
+        if let Some(fallback) = &self.fallback
+            && !self.nodes.contains_key(fallback)
+        {
+            problems.push(FlowProblem::DanglingFallback {
+                node: fallback.clone(),
+            });
+        }
+
+        let mut reachable = HashSet::new();
+        let mut pending = vec![self.start_node.as_str()];
+        pending.extend(self.fallback.as_deref());
+        while let Some(name) = pending.pop() {
+            if !reachable.insert(name) {
+                continue;
+            }
+            if let Some(fan_out) = self.fan_outs.get(name) {
+                pending.extend(fan_out.branches.iter().map(String::as_str));
+                pending.push(&fan_out.join);
+            }
+            if let Some(edges) = self.edges.get(name) {
+                pending.extend(edges.iter().map(|edge| edge.to.as_str()));
+            }
+        }
+        let mut unreachable = self
+            .nodes
+            .keys()
+            .filter(|name| !reachable.contains(name.as_str()))
+            .collect::<Vec<_>>();
+        unreachable.sort();
+        problems.extend(
+            unreachable
+                .into_iter()
+                .map(|node| FlowProblem::Unreachable { node: node.clone() }),
+        );
+
+        problems
+    }
+
+    pub fn graph(&self) -> FlowGraph {
+        let mut nodes = self
+            .nodes
+            .keys()
+            .filter(|name| **name != self.start_node)
+            .cloned()
+            .collect::<Vec<_>>();
+        nodes.sort();
+        nodes.insert(0, self.start_node.clone());
+
+        let mut edges = Vec::new();
+        for from in &nodes {
+            for edge in self.edges.get(from).into_iter().flatten() {
+                edges.push(GraphEdge {
+                    from: from.clone(),
+                    label: edge.label(),
+                    to: edge.to.clone(),
+                });
+            }
+        }
+        let mut fan_outs = self
+            .fan_outs
+            .iter()
+            .map(|(name, fan_out)| GraphFanOut {
+                name: name.clone(),
+                branches: fan_out.branches.clone(),
+                join: fan_out.join.clone(),
+            })
+            .collect::<Vec<_>>();
+        fan_outs.sort_by(|a, b| a.name.cmp(&b.name));
+
+        FlowGraph {
+            start: self.start_node.clone(),
+            nodes,
+            edges,
+            fan_outs,
+        }
+    }
+
+    /// `validate` the flow, fail on the problems that break a run, log the others.
+    pub fn checked(self) -> Result<Self, FlowError> {
+        let (errors, warnings): (Vec<_>, Vec<_>) =
+            self.validate().into_iter().partition(FlowProblem::is_error);
+        for warning in warnings {
+            tracing::warn!("flow problem: {warning}");
+        }
+        if !errors.is_empty() {
+            return Err(FlowError::Invalid(errors));
+        }
+        Ok(self)
+    }
+
+    pub async fn run(&self, context: Context) -> anyhow::Result<Value> {
+        self.run_with_report(context).await.0
+    }
+
+    /// Run the flow, the report of the executed nodes is returned even if the run failed.
+    pub async fn run_with_report(
+        &self,
+        context: Context,
+    ) -> (anyhow::Result<Value>, FlowRunReport) {
+        self.run_checked(context, |_| Ok(())).await
+    }
+
+    /// `run_with_report`, the result fails the run if `check` fails on it.
+    /// The run finishes after the check, with its outcome.
+    pub(crate) async fn run_checked(
+        &self,
+        mut context: Context,
+        check: impl FnOnce(&Value) -> anyhow::Result<()>,
+    ) -> (anyhow::Result<Value>, FlowRunReport) {
+        let started_at = Utc::now();
+        let mut state = RunState::new(context.id(), self.budget.clone());
+        let mut result = self
+            .run_from(&mut context, self.start_node.clone(), &mut state)
+            .await
+            .map(|_| context.get(CONTEXT_RESULT).unwrap_or(&Value::Null).clone());
+        if let Ok(output) = &result
+            && let Err(e) = check(output)
+        {
+            emit(
+                &context,
+                StreamMessage::Error {
+                    message: e.to_string(),
+                },
+            );
+            result = Err(e);
+        }
+        let report = FlowRunReport {
+            run_id: context.id().to_owned(),
+            started_at,
+            finished_at: Utc::now(),
+            nodes: state.nodes,
+            status: RunStatus::of(&result),
+            error: result.as_ref().err().map(ToString::to_string),
+        };
+        emit(
+            &context,
+            StreamMessage::FlowFinished {
+                status: report.status,
+                error: report.error.clone(),
+            },
+        );
+        (result, report)
+    }
+
+    /// Continue the run `run_id` from its last checkpoint, the checkpointed data
+    /// replaces the data of `context`.
+    pub async fn resume(&self, run_id: &str, mut context: Context) -> anyhow::Result<Value> {
+        let store = self
+            .checkpoint
+            .as_ref()
+            .ok_or_else(|| FlowError::Checkpoint("flow has no checkpoint store".to_owned()))?;
+        let checkpoint = store
+            .load(run_id)
+            .await?
+            .ok_or_else(|| FlowError::Checkpoint(format!("no checkpoint for run '{run_id}'")))?;
+        context.restore(checkpoint.context);
+        let mut result = Ok(None);
+        if let Some(next_node_name) = checkpoint.next_node {
+            let mut state = RunState::resumed(run_id, self.budget.clone(), checkpoint.nodes);
+            result = self
+                .run_from(&mut context, next_node_name, &mut state)
+                .await;
+        }
+        emit(
+            &context,
+            StreamMessage::FlowFinished {
+                status: RunStatus::of(&result),
+                error: result.as_ref().err().map(ToString::to_string),
+            },
+        );
+        result?;
+        Ok(context.get(CONTEXT_RESULT).unwrap_or(&Value::Null).clone())
+    }
+
+    /// Run on a borrowed context, returns the status of the last executed node.
+    /// Run by a node of another flow, the run stays within the budget that flow has left
+    /// and its steps count to it. The checkpoints are keyed by `run_id`,
+    /// not to overwrite the ones of a flow running on the same context.
+    pub(crate) async fn run_in(
+        &self,
+        context: &mut Context,
+        run_id: &str,
+    ) -> anyhow::Result<Option<S>> {
+        let outer = context.budget().cloned();
+        let budget = match &outer {
+            Some(outer) => self.budget.within(outer),
+            None => self.budget.clone(),
+        };
+        let mut state = RunState::new(run_id, budget);
+        let result = self
+            .run_from(context, self.start_node.clone(), &mut state)
+            .await;
+        context.set_budget(outer.map(|mut outer| {
+            outer.max_steps = outer
+                .max_steps
+                .map(|max_steps| max_steps.saturating_sub(state.steps));
+            outer
+        }));
+        result
+    }
+
+    /// Run from `node`, the succeeded nodes are compensated if the run stops with an error
+    /// or ends on an unrouted node error. The checkpoint of a finished run is removed,
+    /// the one of a stopped run is kept to `resume` it.
+    async fn run_from(
+        &self,
+        context: &mut Context,
+        node: String,
+        state: &mut RunState,
+    ) -> anyhow::Result<Option<S>> {
+        let result = self.run_steps(context, node, state).await;
+        let failed = match &result {
+            Ok(last) => last.as_ref().is_some_and(|last| last.error.is_some()),
+            Err(_) => true,
+        };
+        if failed {
+            self.compensate(context, state).await;
+        }
+        if result.is_ok() {
+            self.remove_checkpoint(state).await;
+        }
+        result.map(|last| last.map(|last| last.status))
+    }
+
+    /// the result of the last executed node
+    async fn run_steps(
+        &self,
+        context: &mut Context,
+        mut current_node_name: String,
+        state: &mut RunState,
+    ) -> anyhow::Result<Option<NodeResult<S>>> {
+        let mut last = None;
+        let cancel = context.cancellation_token().clone();
+        loop {
+            if cancel.is_cancelled() {
+                return Err(FlowError::Cancelled.into());
+            }
+            let mut before = None;
+            let result = if let Some(fan_out) = self.fan_outs.get(&current_node_name) {
+                let started_at = Utc::now();
+                let deadline = state.deadline();
+                let fan_out_run = self.run_fan_out(&current_node_name, fan_out, context, state);
+                match guarded(deadline, &cancel, fan_out_run).await? {
+                    Ok(()) => {
+                        current_node_name = fan_out.join.clone();
+                        self.save_checkpoint(context, Some(&current_node_name), state)
+                            .await;
+                        continue;
+                    }
+                    Err(e) if stop_error(&e).is_some() => return Err(e),
+                    // a failed fan-out is routed like a failed node
+                    Err(e) => {
+                        let result = Ok(NodeResult::failure(e));
+                        let trace = NodeTrace::default();
+                        let run =
+                            NodeRun::finished(&current_node_name, started_at, 0, &result, trace);
+                        emit_finished(context, &run);
+                        state.nodes.push(run);
+                        result?
+                    }
                 }
             } else {
-                break; // no edges for this node, exit the loop
+                let Some(node) = self.nodes.get(&current_node_name) else {
+                    break;
+                };
+                before = self
+                    .breakpoints
+                    .contains(&current_node_name)
+                    .then(|| context.data().clone());
+                self.run_node(&current_node_name, node.as_ref(), context, state)
+                    .await?
+            };
+            if let Some(error) = &result.error {
+                context.set(CONTEXT_ERROR, Value::from(error.to_string()));
+            }
+            if let Some(before) = before {
+                let paused_at = Instant::now();
+                tokio::select! {
+                    _ = cancel.cancelled() => return Err(FlowError::Cancelled.into()),
+                    _ = Self::pause(&current_node_name, context, &before) => {}
+                }
+                // the time waiting for the user does not count to the deadline
+                state.started_at += paused_at.elapsed();
+            }
+
+            // find the next node based on the result
+            let mut next_node_name = self
+                .edges
+                .get(&current_node_name)
+                .and_then(|edges| select_edge(edges, &result, context))
+                .map(|edge| edge.to.clone())
+                .or_else(|| {
+                    // the fallback does not catch its own errors
+                    self.fallback
+                        .clone()
+                        .filter(|fallback| result.error.is_some() && *fallback != current_node_name)
+                });
+            for observer in self.observers.iter().rev() {
+                observer
+                    .on_edge(&current_node_name, &result, context, &mut next_node_name)
+                    .await;
+            }
+            self.save_checkpoint(context, next_node_name.as_deref(), state)
+                .await;
+            last = Some(result);
+            match next_node_name {
+                Some(to) => current_node_name = to,
+                None => break, // no next node found, exit the loop
             }
         }
 
-        Ok(context.get(CONTEXT_RESULT).unwrap_or(&Value::Null).clone())
+        Ok(last)
+    }
+
+    /// Run one node of the run, fails if the run has to stop.
+    async fn run_node(
+        &self,
+        current_node_name: &str,
+        node: &dyn Node<FlowStatus = S>,
+        context: &mut Context,
+        state: &mut RunState,
+    ) -> anyhow::Result<NodeResult<S>> {
+        let cancel = context.cancellation_token().clone();
+        state.enter(current_node_name)?;
+
+        context.set_node(Some(current_node_name.to_owned()));
+        emit(
+            context,
+            StreamMessage::NodeStarted {
+                node: current_node_name.to_owned(),
+            },
+        );
+        let started_at = Utc::now();
+        let token_usage = context.token_usage();
+        let retry = self.retries.get(current_node_name);
+        let timeout = self.timeouts.get(current_node_name).copied();
+        let cache = self.cache_of(current_node_name);
+        let remaining = state.remaining();
+        context.set_budget(Some(remaining.clone()));
+        let (result, trace) = guarded(
+            state.deadline(),
+            &cancel,
+            run_node_traced(node, context, retry, timeout, cache, &self.observers),
+        )
+        .await
+        .unwrap_or_else(|e| (Err(e.into()), NodeTrace::default()));
+        // the steps of the sub-flows the node ran
+        if let (Some(before), Some(after)) = (
+            remaining.max_steps,
+            context.budget().and_then(|budget| budget.max_steps),
+        ) {
+            state.steps += before.saturating_sub(after);
+        }
+        context.set_budget(None);
+        // cancellation and the budget stop the run, also when a sub-flow ran into them,
+        // the other errors are routed
+        let stopped = result
+            .as_ref()
+            .ok()
+            .and_then(|result| result.error.as_deref())
+            .and_then(stop_error);
+        let result = match (result, stopped) {
+            (_, Some(stopped)) => Err(stopped.into()),
+            (Err(e), None) if stop_error(&e).is_none() => Ok(NodeResult::failure(e)),
+            (result, None) => result,
+        };
+        let run = NodeRun::finished(
+            current_node_name,
+            started_at,
+            context.token_usage() - token_usage,
+            &result,
+            trace,
+        );
+        emit_finished(context, &run);
+        state.nodes.push(run);
+        result
+    }
+
+    /// `compensate` the succeeded nodes of the run, latest first
+    async fn compensate(&self, context: &mut Context, state: &RunState) {
+        let succeeded = state
+            .nodes
+            .iter()
+            .rev()
+            .filter(|run| run.error.is_none() && !run.failed);
+        for run in succeeded {
+            let Some(node) = self.nodes.get(&run.node) else {
+                continue;
+            };
+            context.set_node(Some(run.node.clone()));
+            emit(
+                context,
+                StreamMessage::Procedure(format!("compensating {}", run.node)),
+            );
+            if let Err(e) = node.compensate(context).await {
+                tracing::error!(
+                    "Failed to compensate node '{}' of run {}: {e}",
+                    run.node,
+                    context.id()
+                );
+            }
+        }
+        context.set_node(None);
+    }
+
+    /// Hand the writes of the node to the breakpoint listener of the context
+    /// and wait for the (edited) values.
+    async fn pause(node_name: &str, context: &mut Context, before: &HashMap<String, Value>) {
+        let Some(breakpoints) = context.breakpoints() else {
+            tracing::warn!("breakpoint at '{node_name}' but no one listens on the context");
+            return;
+        };
+        let (paused, edits) = PausedNode::new(node_name.to_owned(), context.writes_since(before));
+        if breakpoints.send(paused).await.is_err() {
+            return; // listener is gone, keep running
+        }
+        if let Ok(edits) = edits.await {
+            for (key, value) in edits {
+                context.set(&key, value);
+            }
+        }
+    }
+
+    /// Run the branches on their own copies of the context, then merge the writes
+    /// of the succeeded ones back into `context`.
+    async fn run_fan_out(
+        &self,
+        name: &str,
+        fan_out: &FanOut,
+        context: &mut Context,
+        state: &mut RunState,
+    ) -> anyhow::Result<()> {
+        let mut branches = FuturesUnordered::new();
+        let base_usage = context.token_usage();
+        for (index, branch) in fan_out.branches.iter().enumerate() {
+            let Some(node) = self.nodes.get(branch) else {
+                tracing::warn!("fan-out '{name}' branch '{branch}' is not a node");
+                continue;
+            };
+            state.enter(branch)?;
+            let retry = self.retries.get(branch);
+            let timeout = self.timeouts.get(branch).copied();
+            let cache = self.cache_of(branch);
+            let observers = &self.observers;
+            let mut branch_context = context.clone();
+            branch_context.set_node(Some(branch.clone()));
+            branch_context.set_budget(Some(state.remaining()));
+            branches.push(async move {
+                emit(
+                    &branch_context,
+                    StreamMessage::NodeStarted {
+                        node: branch.clone(),
+                    },
+                );
+                let started_at = Utc::now();
+                let (result, trace) = run_node_traced(
+                    node.as_ref(),
+                    &mut branch_context,
+                    retry,
+                    timeout,
+                    cache,
+                    observers,
+                )
+                .await;
+                let token_usage = branch_context.token_usage() - base_usage;
+                let mut run = NodeRun::finished(branch, started_at, token_usage, &result, trace);
+                run.fan_out = Some(name.to_owned());
+                emit_finished(&branch_context, &run);
+                (index, result, branch_context, run)
+            });
+        }
+
+        let required = fan_out.required();
+        let mut succeeded = Vec::new();
+        let mut token_usage = 0;
+        while let Some((index, result, branch_context, run)) = branches.next().await {
+            token_usage += run.token_usage;
+            state.nodes.push(run);
+            match result {
+                Ok(result) if result.status != S::failed() => {
+                    succeeded.push((index, branch_context.writes_since(context.data())));
+                }
+                Ok(result) => tracing::warn!(
+                    "fan-out '{name}' branch '{}' failed: {}",
+                    fan_out.branches[index],
+                    result.message
+                ),
+                Err(e) => tracing::warn!(
+                    "fan-out '{name}' branch '{}' failed: {e}",
+                    fan_out.branches[index]
+                ),
+            }
+            if succeeded.len() >= required {
+                break; // enough branches, the remaining ones are dropped
+            }
+        }
+        drop(branches);
+        context.add_token_usage(token_usage);
+        if succeeded.len() < required {
+            return Err(FlowError::FanOutFailed {
+                fan_out: name.to_owned(),
+                succeeded: succeeded.len(),
+                required,
+            }
+            .into());
+        }
+
+        succeeded.sort_by_key(|(index, _)| *index);
+        let writes = succeeded
+            .into_iter()
+            .map(|(index, writes)| (fan_out.branches[index].clone(), writes))
+            .collect();
+        fan_out.merge_into(name, context, writes)?;
+        Ok(())
+    }
+
+    fn cache_of(&self, node: &str) -> Option<(&NodeCache, &CachePolicy)> {
+        self.cache.as_ref().zip(self.cached.get(node))
+    }
+
+    async fn save_checkpoint(&self, context: &Context, next_node: Option<&str>, state: &RunState) {
+        let Some(store) = &self.checkpoint else {
+            return;
+        };
+        let checkpoint = FlowCheckpoint {
+            run_id: state.run_id.clone(),
+            next_node: next_node.map(str::to_owned),
+            context: context.snapshot(),
+            nodes: state.nodes.clone(),
+            updated_at: mongodb::bson::DateTime::now(),
+        };
+        // a lost checkpoint should not fail the run itself
+        if let Err(e) = store.save(&checkpoint).await {
+            tracing::error!(
+                "Failed to save checkpoint of run {}: {e}",
+                checkpoint.run_id
+            );
+        }
+    }
+
+    async fn remove_checkpoint(&self, state: &RunState) {
+        let Some(store) = &self.checkpoint else {
+            return;
+        };
+        if let Err(e) = store.remove(&state.run_id).await {
+            tracing::error!("Failed to remove checkpoint of run {}: {e}", state.run_id);
+        }
+    }
+}
+
+/// `fut` bounded by the deadline of the budget, `(started_at, deadline)`,
+/// and the cancellation of the run
+async fn guarded<T>(
+    deadline: Option<(Instant, Duration)>,
+    cancel: &CancellationToken,
+    fut: impl Future<Output = T>,
+) -> Result<T, FlowError> {
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(FlowError::Cancelled),
+        result = within_deadline(deadline, fut) => result,
+    }
+}
+
+async fn within_deadline<T>(
+    deadline: Option<(Instant, Duration)>,
+    fut: impl Future<Output = T>,
+) -> Result<T, FlowError> {
+    match deadline {
+        Some((started_at, deadline)) => {
+            let remaining = deadline.saturating_sub(started_at.elapsed());
+            tokio::time::timeout(remaining, fut)
+                .await
+                .map_err(|_| FlowError::BudgetExceeded(BudgetExceeded::Deadline(deadline)))
+        }
+        None => Ok(fut.await),
+    }
+}
+
+/// the errors that stop the run instead of being routed: cancellation and the budget
+fn stop_error(error: &anyhow::Error) -> Option<FlowError> {
+    match error.downcast_ref::<FlowError>()? {
+        FlowError::Cancelled => Some(FlowError::Cancelled),
+        FlowError::BudgetExceeded(exceeded) => Some(FlowError::BudgetExceeded(exceeded.clone())),
+        _ => None,
+    }
+}
+
+// the flow events are best effort, a run without listeners is fine
+fn emit(context: &Context, message: StreamMessage) {
+    context.stream(FLOW_STREAM).send(message);
+}
+
+fn emit_finished(context: &Context, run: &NodeRun) {
+    if let Some(error) = &run.error {
+        emit(
+            context,
+            StreamMessage::Error {
+                message: error.clone(),
+            },
+        );
+    }
+    emit(
+        context,
+        StreamMessage::NodeFinished {
+            node: run.node.clone(),
+            status: run.status.clone(),
+            message: run.message.clone(),
+        },
+    );
+}
+
+/// Bookkeeping of one run to check it against the [`FlowBudget`]
+struct RunState {
+    /// the key of the checkpoints
+    run_id: String,
+    budget: FlowBudget,
+    started_at: Instant,
+    steps: usize,
+    visits: HashMap<String, usize>,
+    nodes: Vec<NodeRun>,
+}
+
+impl RunState {
+    fn new(run_id: &str, budget: FlowBudget) -> Self {
+        RunState {
+            run_id: run_id.to_owned(),
+            budget,
+            started_at: Instant::now(),
+            steps: 0,
+            visits: HashMap::new(),
+            nodes: Vec::new(),
+        }
+    }
+
+    /// continue counting from the nodes of a checkpoint
+    fn resumed(run_id: &str, budget: FlowBudget, nodes: Vec<NodeRun>) -> Self {
+        let mut state = RunState::new(run_id, budget);
+        for run in &nodes {
+            state.steps += 1;
+            *state.visits.entry(run.node.clone()).or_default() += 1;
+        }
+        state.nodes = nodes;
+        state
+    }
+
+    fn deadline(&self) -> Option<(Instant, Duration)> {
+        self.budget
+            .deadline
+            .map(|deadline| (self.started_at, deadline))
+    }
+
+    /// the budget left to the rest of the run
+    fn remaining(&self) -> FlowBudget {
+        FlowBudget {
+            max_steps: self
+                .budget
+                .max_steps
+                .map(|max_steps| max_steps.saturating_sub(self.steps)),
+            max_visits: self.budget.max_visits,
+            deadline: self
+                .budget
+                .deadline
+                .map(|deadline| deadline.saturating_sub(self.started_at.elapsed())),
+        }
+    }
+
+    /// count one more execution of `node`
+    fn enter(&mut self, node: &str) -> Result<(), FlowError> {
+        let budget = &self.budget;
+        self.steps += 1;
+        if let Some(max_steps) = budget.max_steps.filter(|max| self.steps > *max) {
+            return Err(FlowError::BudgetExceeded(BudgetExceeded::MaxSteps(
+                max_steps,
+            )));
+        }
+        let visits = self.visits.entry(node.to_owned()).or_default();
+        *visits += 1;
+        if let Some(max_visits) = budget.max_visits.filter(|max| *visits > *max) {
+            return Err(FlowError::BudgetExceeded(BudgetExceeded::MaxVisits {
+                node: node.to_owned(),
+                limit: max_visits,
+            }));
+        }
+        if let Some(deadline) = budget.deadline.filter(|d| self.started_at.elapsed() >= *d) {
+            return Err(FlowError::BudgetExceeded(BudgetExceeded::Deadline(
+                deadline,
+            )));
+        }
+        Ok(())
     }
 }
 
@@ -105,20 +1004,21 @@ mod tests {
 
     #[allow(unused_imports)]
     use super::*;
-    use crate::core::context::Context;
+    use crate::core::{
+        context::Context,
+        fan_out::MergeStrategy,
+        node::NodeResult,
+        stream_message::{StreamMessage, TaggedMessage},
+    };
 
-    #[derive(Default, PartialEq, Eq)]
+    #[derive(Debug, PartialEq, Eq, Status)]
     enum MyStatus {
-        #[default]
+        #[status(default)]
         Done,
         Repeat,
+        #[status(failed)]
         Failed,
     }
-    impl Status for MyStatus {
-        fn failed() -> Self {
-            MyStatus::Failed
-        }
-    }
     struct StartNode {}
     #[async_trait::async_trait]
     impl Node for StartNode {
@@ -162,4 +1062,666 @@ mod tests {
             ]
         };
     }
+
+    struct RepeatNode {}
+    #[async_trait::async_trait]
+    impl Node for RepeatNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            Ok(Value::Null)
+        }
+
+        async fn after_exec(
+            &self,
+            context: &mut Context,
+            result: &anyhow::Result<Value>,
+        ) -> anyhow::Result<NodeResult<MyStatus>> {
+            Ok(NodeResult::new(MyStatus::Repeat, String::new()))
+        }
+    }
+
+    fn budget_error(result: anyhow::Result<Value>) -> BudgetExceeded {
+        match result.unwrap_err().downcast::<FlowError>().unwrap() {
+            FlowError::BudgetExceeded(exceeded) => exceeded,
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_budget() {
+        let repeat = flow! {
+            start: ("start", Arc::new(RepeatNode {})),
+            nodes: [],
+            edges: [("start", MyStatus::Repeat, "start")]
+        };
+
+        let f = repeat.with_budget(FlowBudget::default().with_max_steps(5));
+        let exceeded = budget_error(f.run(Context::new()).await);
+        assert_eq!(exceeded, BudgetExceeded::MaxSteps(5));
+
+        let f = f.with_budget(FlowBudget::default().with_max_visits(3));
+        let exceeded = budget_error(f.run(Context::new()).await);
+        assert_eq!(
+            exceeded,
+            BudgetExceeded::MaxVisits {
+                node: "start".to_owned(),
+                limit: 3
+            }
+        );
+
+        let deadline = Duration::from_millis(20);
+        let f = f.with_budget(FlowBudget::default().with_deadline(deadline));
+        let exceeded = budget_error(f.run(Context::new()).await);
+        assert_eq!(exceeded, BudgetExceeded::Deadline(deadline));
+    }
+
+    struct WriteNode {
+        key: &'static str,
+        fail: bool,
+    }
+    #[async_trait::async_trait]
+    impl Node for WriteNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            if self.fail {
+                return Err(anyhow::anyhow!("{} failed", self.key));
+            }
+            context.set(self.key, Value::String(self.key.to_owned()));
+            Ok(Value::String(self.key.to_owned()))
+        }
+    }
+
+    struct JoinNode {}
+    #[async_trait::async_trait]
+    impl Node for JoinNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            let keys = [
+                "summary.summary",
+                "keywords.keywords",
+                "translate",
+                "keywords.result",
+            ];
+            Ok(keys
+                .iter()
+                .map(|key| context.get(key).cloned().unwrap_or(Value::Null))
+                .collect())
+        }
+    }
+
+    fn write_node(key: &'static str, fail: bool) -> Arc<WriteNode> {
+        Arc::new(WriteNode { key, fail })
+    }
+
+    #[tokio::test]
+    async fn test_flow_fan_out() {
+        let mut f = flow! {
+            start: ("start", Arc::new(StartNode {})),
+            nodes: [
+                ("summary", write_node("summary", false)),
+                ("keywords", write_node("keywords", false)),
+                ("translate", write_node("translate", true)),
+                ("join", Arc::new(EndNode {})),
+            ],
+            edges: [("start", MyStatus::Done, "analyze")]
+        };
+        f.add_fan_out(
+            "analyze",
+            FanOut::new(&["summary", "keywords", "translate"], "join"),
+        );
+        // the failed fan-out ends the run like a failed node without an error edge
+        let (result, report) = f.run_with_report(Context::new()).await;
+        result.unwrap();
+        let analyze = report.nodes.last().unwrap();
+        assert_eq!(analyze.node, "analyze");
+        assert_eq!(analyze.status.as_deref(), Some("Failed"));
+        assert!(
+            analyze
+                .error
+                .as_deref()
+                .unwrap()
+                .contains("needs 3 branches, only 2 succeeded")
+        );
+
+        f.add_fan_out(
+            "analyze",
+            FanOut::new(&["summary", "keywords", "translate"], "join")
+                .at_least(2)
+                .with_merge(MergeStrategy::Namespaced),
+        );
+        f.add_node("join", Arc::new(JoinNode {}));
+        let result = f.run(Context::new()).await.unwrap();
+        assert_eq!(
+            result,
+            serde_json::json!(["summary", "keywords", null, "keywords"])
+        );
+
+        // a branch that is not a node takes no step
+        f.add_fan_out(
+            "analyze",
+            FanOut::new(&["summary", "keywords", "missing"], "join")
+                .at_least(2)
+                .with_merge(MergeStrategy::Namespaced),
+        );
+        let f = f.with_budget(FlowBudget::default().with_max_steps(4));
+        f.run(Context::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_fan_out_fail_on_conflict() {
+        let mut f = flow! {
+            start: ("start", Arc::new(StartNode {})),
+            nodes: [
+                ("summary", write_node("summary", false)),
+                ("keywords", write_node("keywords", false)),
+                ("join", Arc::new(EchoNode { key: "keywords.result" })),
+            ],
+            edges: [("start", MyStatus::Done, "analyze")]
+        };
+        // both branches write `result` in the default `after_exec`
+        f.add_fan_out(
+            "analyze",
+            FanOut::new(&["summary", "keywords"], "join").with_merge(MergeStrategy::FailOnConflict),
+        );
+        let mut context = Context::new();
+        f.run_in(&mut context, "run").await.unwrap();
+        assert_eq!(context.get("summary"), Some(&Value::from("summary")));
+        assert_eq!(context.get("keywords"), Some(&Value::from("keywords")));
+        assert_eq!(context.get("summary.result"), Some(&Value::from("summary")));
+        assert_eq!(context.get(CONTEXT_RESULT), Some(&Value::from("keywords")));
+    }
+
+    #[tokio::test]
+    async fn test_flow_fan_out_error_routing() {
+        let compensated = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut f = flow! {
+            start: ("draft", Arc::new(SaveNode { compensated: compensated.clone(), key: "draft" })),
+            nodes: [
+                ("review", Arc::new(SaveNode { compensated: compensated.clone(), key: "review" })),
+                ("translate", write_node("translate", true)),
+                ("join", Arc::new(EndNode {})),
+                ("handler", Arc::new(EchoNode { key: CONTEXT_ERROR })),
+            ],
+            edges: [("draft", MyStatus::Done, "analyze")]
+        };
+        f.add_fan_out("analyze", FanOut::new(&["review", "translate"], "join"));
+        f.add_error_edge("analyze", "handler");
+        let result = f.run(Context::new()).await.unwrap();
+        assert!(
+            result
+                .as_str()
+                .unwrap()
+                .starts_with("FlowError FanOutFailed: 'analyze'")
+        );
+        assert!(compensated.lock().unwrap().is_empty());
+
+        // a merge conflict goes to the fallback
+        f.edges.remove("analyze");
+        f.add_node("translate", write_node("draft", false));
+        f.add_node("review", write_node("draft", false));
+        f.add_fan_out(
+            "analyze",
+            FanOut::new(&["review", "translate"], "join").with_merge(MergeStrategy::FailOnConflict),
+        );
+        let mut f = f.with_fallback("handler");
+        let result = f.run(Context::new()).await.unwrap();
+        assert!(
+            result
+                .as_str()
+                .unwrap()
+                .starts_with("FlowError MergeConflict: 'analyze'")
+        );
+
+        // without a route the succeeded nodes are compensated
+        f.fallback = None;
+        f.add_node(
+            "review",
+            Arc::new(SaveNode {
+                compensated: compensated.clone(),
+                key: "review",
+            }),
+        );
+        f.add_node("translate", write_node("translate", true));
+        f.add_fan_out("analyze", FanOut::new(&["review", "translate"], "join"));
+        f.run(Context::new()).await.unwrap();
+        assert_eq!(*compensated.lock().unwrap(), vec!["review", "draft"]);
+
+        // the budget still stops the run
+        let f = f.with_budget(FlowBudget::default().with_max_steps(2));
+        let exceeded = budget_error(f.run(Context::new()).await);
+        assert_eq!(exceeded, BudgetExceeded::MaxSteps(2));
+    }
+
+    struct EchoNode {
+        key: &'static str,
+    }
+    #[async_trait::async_trait]
+    impl Node for EchoNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            Ok(context.get(self.key).cloned().unwrap_or_default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_breakpoint() {
+        let mut f = flow! {
+            start: ("writer", write_node("draft", false)),
+            nodes: [("editor", Arc::new(EchoNode { key: "draft" }))],
+            edges: [("writer", MyStatus::Done, "editor")]
+        };
+        f.add_breakpoint("writer");
+
+        let mut context = Context::new();
+        let mut breakpoints = context.enable_breakpoints();
+        let run = tokio::spawn(async move { f.run(context).await });
+
+        let paused = breakpoints.recv().await.unwrap();
+        assert_eq!(paused.node, "writer");
+        assert_eq!(paused.writes.get("draft"), Some(&Value::from("draft")));
+        paused.resume_with(HashMap::from([(
+            "draft".to_owned(),
+            Value::from("edited draft"),
+        )]));
+
+        let result = run.await.unwrap().unwrap();
+        assert_eq!(result, Value::from("edited draft"));
+    }
+
+    /// records its runs, cancels the run on its first one if `interrupt`
+    struct StepNode {
+        name: &'static str,
+        interrupt: bool,
+        runs: Arc<std::sync::Mutex<Vec<&'static str>>>,
+    }
+    #[async_trait::async_trait]
+    impl Node for StepNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            let mut runs = self.runs.lock().unwrap();
+            if self.interrupt && !runs.contains(&self.name) {
+                context.cancel();
+            }
+            runs.push(self.name);
+            Ok(Value::from(self.name))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_resume() {
+        let runs = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let step = |name, interrupt| {
+            Arc::new(StepNode {
+                name,
+                interrupt,
+                runs: runs.clone(),
+            })
+        };
+        let store = CheckpointStore::memory();
+        let f = flow! {
+            start: ("writer", step("writer", false)),
+            nodes: [("editor", step("editor", true)), ("publisher", step("publisher", false))],
+            edges: [
+                ("writer", MyStatus::Done, "editor"),
+                ("editor", MyStatus::Done, "publisher"),
+            ]
+        }
+        .with_checkpoint(store.clone());
+
+        let context = Context::new();
+        let run_id = context.id().to_owned();
+        let err = f.run(context).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlowError>(),
+            Some(FlowError::Cancelled)
+        ));
+        let checkpoint = store.load(&run_id).await.unwrap().unwrap();
+        assert_eq!(checkpoint.next_node.as_deref(), Some("publisher"));
+        assert_eq!(checkpoint.nodes.len(), 2);
+
+        let result = f.resume(&run_id, Context::new()).await.unwrap();
+        assert_eq!(result, Value::from("publisher"));
+        assert_eq!(*runs.lock().unwrap(), ["writer", "editor", "publisher"]);
+        // the finished run has no checkpoint left
+        assert!(store.load(&run_id).await.unwrap().is_none());
+    }
+
+    struct UsageNode {
+        tokens: i64,
+    }
+    #[async_trait::async_trait]
+    impl Node for UsageNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            context.add_token_usage(self.tokens);
+            Ok(Value::Null)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_report() {
+        let f = flow! {
+            start: ("start", Arc::new(UsageNode { tokens: 10 })),
+            nodes: [("draft", write_node("draft", true))],
+            edges: [("start", MyStatus::Done, "draft")]
+        };
+        let context = Context::new();
+        let run_id = context.id().to_owned();
+        let (result, report) = f.run_with_report(context).await;
+        assert!(result.is_ok());
+        assert_eq!(report.run_id, run_id);
+        assert_eq!(report.error, None);
+        assert_eq!(report.token_usage(), 10);
+        let nodes = report
+            .nodes
+            .iter()
+            .map(|n| (n.node.as_str(), n.status.as_deref(), n.error.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            nodes,
+            vec![
+                ("start", Some("Done"), None),
+                ("draft", Some("Failed"), Some("draft failed")),
+            ]
+        );
+        assert!(report.nodes[0].started_at <= report.nodes[0].finished_at);
+
+        let repeat = flow! {
+            start: ("start", Arc::new(RepeatNode {})),
+            nodes: [],
+            edges: [("start", MyStatus::Repeat, "start")]
+        }
+        .with_budget(FlowBudget::default().with_max_steps(2));
+        let (result, report) = repeat.run_with_report(Context::new()).await;
+        assert!(result.is_err());
+        assert_eq!(report.nodes.len(), 2);
+        assert!(report.error.unwrap().contains("more than 2 steps"));
+    }
+
+    struct FlakyNode {
+        failures: std::sync::atomic::AtomicUsize,
+    }
+    #[async_trait::async_trait]
+    impl Node for FlakyNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            let left = self.failures.load(std::sync::atomic::Ordering::SeqCst);
+            if left > 0 {
+                self.failures
+                    .store(left - 1, std::sync::atomic::Ordering::SeqCst);
+                return Err(anyhow::anyhow!("timeout"));
+            }
+            Ok(Value::from("done"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_retry() {
+        let flaky = || {
+            Arc::new(FlakyNode {
+                failures: std::sync::atomic::AtomicUsize::new(2),
+            })
+        };
+        let policy =
+            RetryPolicy::new(3).with_backoff(Duration::from_millis(1), Duration::from_millis(5));
+        let mut f = flow!(start: ("flaky", flaky()));
+        f.set_retry("flaky", policy.clone());
+
+        let context = Context::new();
+        let mut stream = context.listen();
+        let (result, report) = f.run_with_report(context).await;
+        assert_eq!(result.unwrap(), Value::from("done"));
+        assert_eq!(report.nodes[0].retried, vec!["timeout", "timeout"]);
+        let mut messages = vec![];
+        while let Ok(Some(TaggedMessage { message, .. })) =
+            tokio::time::timeout(Duration::from_millis(10), stream.next()).await
+        {
+            messages.push(message);
+        }
+        let procedures = messages
+            .iter()
+            .filter_map(|m| match m {
+                StreamMessage::Procedure(p) => Some(p.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(procedures, vec!["retrying 2/3", "retrying 3/3"]);
+        assert_eq!(
+            messages.first(),
+            Some(&StreamMessage::NodeStarted {
+                node: "flaky".to_owned()
+            })
+        );
+        assert_eq!(
+            messages.last(),
+            Some(&StreamMessage::FlowFinished {
+                status: RunStatus::Finished,
+                error: None
+            })
+        );
+
+        let mut f = flow!(start: ("flaky", flaky()));
+        f.set_retry("flaky", policy.retry_if(|e| e.to_string() != "timeout"));
+        let (_, report) = f.run_with_report(Context::new()).await;
+        assert_eq!(report.nodes[0].status.as_deref(), Some("Failed"));
+        assert!(report.nodes[0].retried.is_empty());
+    }
+
+    struct SlowNode {}
+    #[async_trait::async_trait]
+    impl Node for SlowNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(Value::Null)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_cancel() {
+        let f = flow! {
+            start: ("start", Arc::new(StartNode {})),
+            nodes: [("slow", Arc::new(SlowNode {}))],
+            edges: [("start", MyStatus::Done, "slow")]
+        };
+        let context = Context::new();
+        let cancel = context.cancellation_token().clone();
+        let run = tokio::spawn(async move { f.run_with_report(context).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        cancel.cancel();
+        let (result, report) = run.await.unwrap();
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<FlowError>(),
+            Some(FlowError::Cancelled)
+        ));
+        assert_eq!(report.status, RunStatus::Cancelled);
+        assert_eq!(report.nodes.len(), 2);
+        assert_eq!(report.nodes[1].status, None);
+
+        let mut f = flow!(start: ("slow", Arc::new(SlowNode {})));
+        f.set_timeout("slow", Duration::from_millis(10));
+        let (result, report) = f.run_with_report(Context::new()).await;
+        assert!(result.is_ok());
+        assert_eq!(report.status, RunStatus::Finished);
+        assert_eq!(report.nodes[0].status.as_deref(), Some("Failed"));
+        assert!(report.nodes[0].error.as_ref().unwrap().contains("Timeout"));
+    }
+
+    #[test]
+    fn test_flow_validate() {
+        let mut f = flow! {
+            start: ("start", Arc::new(StartNode {})),
+            nodes: [("end", Arc::new(EndNode {})), ("orphan", Arc::new(EndNode {}))],
+            edges: [
+                ("start", MyStatus::Done, "end"),
+                ("start", MyStatus::Done, "start"),
+                ("start", MyStatus::Failed, "missing"),
+                ("end", MyStatus::Repeat, "start"),
+            ]
+        };
+        f.add_fan_out("end", FanOut::new(&["start", "nowhere"], "end"));
+        assert_eq!(
+            f.validate(),
+            vec![
+                FlowProblem::NameConflict {
+                    name: "end".to_owned()
+                },
+                FlowProblem::DanglingFanOut {
+                    fan_out: "end".to_owned(),
+                    node: "nowhere".to_owned()
+                },
+                FlowProblem::NoFailureExit {
+                    node: "end".to_owned()
+                },
+                FlowProblem::DuplicateCondition {
+                    from: "start".to_owned(),
+                    condition: "Done".to_owned()
+                },
+                FlowProblem::DanglingEdge {
+                    from: "start".to_owned(),
+                    to: "missing".to_owned()
+                },
+                FlowProblem::Unreachable {
+                    node: "orphan".to_owned()
+                },
+            ]
+        );
+        let err = f.checked().err().unwrap();
+        assert!(matches!(err, FlowError::Invalid(problems) if problems.len() == 3));
+    }
+    struct PrepareFailNode {}
+    #[async_trait::async_trait]
+    impl Node for PrepareFailNode {
+        type FlowStatus = MyStatus;
+
+        async fn prepare(&self, context: &mut Context) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("no permission"))
+        }
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            Ok(Value::Null)
+        }
+    }
+
+    struct SaveNode {
+        compensated: Arc<std::sync::Mutex<Vec<&'static str>>>,
+        key: &'static str,
+    }
+    #[async_trait::async_trait]
+    impl Node for SaveNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            Ok(Value::Null)
+        }
+
+        async fn compensate(&self, context: &mut Context) -> anyhow::Result<()> {
+            self.compensated.lock().unwrap().push(self.key);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_error_routing() {
+        let mut f = flow! {
+            start: ("check", Arc::new(PrepareFailNode {})),
+            nodes: [
+                ("handler", Arc::new(EchoNode { key: CONTEXT_ERROR })),
+                ("end", Arc::new(EndNode {})),
+            ],
+            edges: [("check", MyStatus::Failed, "end")]
+        };
+        f.add_error_edge("check", "handler");
+        let (result, report) = f.run_with_report(Context::new()).await;
+        assert_eq!(result.unwrap(), Value::from("no permission"));
+        assert_eq!(report.nodes[0].status.as_deref(), Some("Failed"));
+        assert_eq!(report.nodes[0].error.as_deref(), Some("no permission"));
+
+        let compensated = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let save = |key| {
+            Arc::new(SaveNode {
+                compensated: compensated.clone(),
+                key,
+            })
+        };
+        let f = flow! {
+            start: ("draft", save("draft")),
+            nodes: [
+                ("review", save("review")),
+                ("publish", write_node("publish", true)),
+                ("handler", Arc::new(EchoNode { key: CONTEXT_ERROR })),
+            ],
+            edges: [
+                ("draft", MyStatus::Done, "review"),
+                ("review", MyStatus::Done, "publish"),
+            ]
+        };
+        let f = f.with_fallback("handler");
+        // the fallback is reachable, only the failed statuses are not routed
+        assert!(
+            f.validate()
+                .iter()
+                .all(|problem| matches!(problem, FlowProblem::NoFailureExit { .. }))
+        );
+        assert_eq!(
+            f.run(Context::new()).await.unwrap(),
+            Value::from("publish failed")
+        );
+        assert!(compensated.lock().unwrap().is_empty());
+
+        // the fallback fails too, the run ends on the error
+        let mut f = f;
+        f.add_node("handler", Arc::new(PrepareFailNode {}));
+        f.run(Context::new()).await.unwrap();
+        assert_eq!(*compensated.lock().unwrap(), vec!["review", "draft"]);
+    }
+
+    #[tokio::test]
+    async fn test_flow_predicate_edges() {
+        let mut f = flow! {
+            start: ("writer", write_node("draft", false)),
+            nodes: [
+                ("editor", Arc::new(EchoNode { key: "draft" })),
+                ("shorten", write_node("short", false)),
+            ],
+            edges: [("writer", MyStatus::Done, "editor")]
+        };
+        f.insert_edge(
+            "writer",
+            Edge::when("too long", "shorten", |_, context| {
+                context
+                    .get("draft")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .len()
+                    > 3
+            })
+            .with_priority(1),
+        );
+        f.add_default_edge("writer", "editor");
+        assert!(f.validate().is_empty());
+        assert_eq!(f.run(Context::new()).await.unwrap(), Value::from("short"));
+
+        // nothing matches, the default edge is taken
+        f.add_edge_if("shorten", "never", |_, _| false, "writer");
+        f.add_default_edge("shorten", "editor");
+        assert_eq!(f.run(Context::new()).await.unwrap(), Value::from("draft"));
+
+        f.add_default_edge("shorten", "writer");
+        assert_eq!(
+            f.validate(),
+            vec![FlowProblem::DuplicateCondition {
+                from: "shorten".to_owned(),
+                condition: "else".to_owned()
+            }]
+        );
+    }
 }
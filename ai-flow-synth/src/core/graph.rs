@@ -0,0 +1,316 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use super::report::NodeRun;
+
+/// The node/edge graph of a `Flow`, statuses are labeled by `Status::label`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowGraph {
+    pub start: String,
+    /// the start node first, then the others sorted by name
+    pub nodes: Vec<String>,
+    pub edges: Vec<GraphEdge>,
+    pub fan_outs: Vec<GraphFanOut>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphEdge {
+    pub from: String,
+    pub label: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphFanOut {
+    pub name: String,
+    pub branches: Vec<String>,
+    pub join: String,
+}
+
+/// What a finished run did, derived from its node runs
+struct RunAnnotation {
+    /// last status of every visited node and whether it failed,
+    /// nodes that stopped the run have none
+    statuses: HashMap<String, (Option<String>, bool)>,
+    /// `(from, to)` of every step taken, a fan-out counts as the step to its name
+    steps: HashSet<(String, String)>,
+}
+
+impl RunAnnotation {
+    fn new(run: &[NodeRun]) -> Self {
+        let mut steps = HashSet::new();
+        let mut previous: Option<&str> = None;
+        for node in run {
+            let to = node.fan_out.as_deref().unwrap_or(&node.node);
+            if let Some(from) = previous {
+                steps.insert((from.to_owned(), to.to_owned()));
+            }
+            if node.fan_out.is_none() {
+                previous = Some(&node.node);
+            }
+        }
+        RunAnnotation {
+            statuses: run
+                .iter()
+                .map(|r| (r.node.clone(), (r.status.clone(), r.failed)))
+                .collect(),
+            steps,
+        }
+    }
+
+    /// the label of the node's status, `None` if it was not visited
+    fn status(&self, node: &str) -> Option<&str> {
+        self.statuses
+            .get(node)
+            .map(|(status, failed)| match status {
+                Some(status) => status.as_str(),
+                None if *failed => "error",
+                None => "visited",
+            })
+    }
+
+    fn failed(&self, node: &str) -> bool {
+        self.statuses.get(node).is_some_and(|(_, failed)| *failed)
+    }
+
+    fn took(&self, edge: &GraphEdge) -> bool {
+        self.steps.contains(&(edge.from.clone(), edge.to.clone()))
+    }
+}
+
+impl FlowGraph {
+    fn ids(&self) -> HashMap<&str, String> {
+        self.nodes
+            .iter()
+            .chain(self.fan_outs.iter().map(|f| &f.name))
+            .enumerate()
+            .map(|(i, name)| (name.as_str(), format!("n{i}")))
+            .collect()
+    }
+
+    /// Mermaid flowchart, with `run` the visited nodes are colored
+    /// and the edges taken are drawn thick.
+    pub fn to_mermaid(&self, run: Option<&[NodeRun]>) -> String {
+        let ids = self.ids();
+        let id = |name: &str| ids.get(name).cloned().unwrap_or_else(|| escape_id(name));
+        let run = run.map(RunAnnotation::new);
+        let mut lines = vec!["flowchart TD".to_owned()];
+
+        for node in &self.nodes {
+            let label = match run.as_ref().and_then(|r| r.status(node)) {
+                Some(status) => format!("{node}<br/>{status}"),
+                None => node.clone(),
+            };
+            lines.push(format!("    {}[\"{}\"]", id(node), escape_mermaid(&label)));
+        }
+        for fan_out in &self.fan_outs {
+            lines.push(format!(
+                "    {}{{{{\"{}\"}}}}",
+                id(&fan_out.name),
+                escape_mermaid(&fan_out.name)
+            ));
+        }
+
+        // edges are numbered in the order they are declared, for `linkStyle`
+        let mut taken = Vec::new();
+        for (index, edge) in self.edges.iter().enumerate() {
+            lines.push(format!(
+                "    {} -->|\"{}\"| {}",
+                id(&edge.from),
+                escape_mermaid(&edge.label),
+                id(&edge.to)
+            ));
+            if run.as_ref().is_some_and(|r| r.took(edge)) {
+                taken.push(index);
+            }
+        }
+        for fan_out in &self.fan_outs {
+            for branch in &fan_out.branches {
+                lines.push(format!("    {} -.-> {}", id(&fan_out.name), id(branch)));
+                lines.push(format!("    {} -.-> {}", id(branch), id(&fan_out.join)));
+            }
+        }
+
+        if let Some(run) = &run {
+            lines.push("    classDef visited fill:#d4f7d4,stroke:#2e7d32".to_owned());
+            lines.push("    classDef failed fill:#f7d4d4,stroke:#c62828".to_owned());
+            for node in &self.nodes {
+                if run.failed(node) {
+                    lines.push(format!("    class {} failed", id(node)));
+                } else if run.status(node).is_some() {
+                    lines.push(format!("    class {} visited", id(node)));
+                }
+            }
+            for index in taken {
+                lines.push(format!("    linkStyle {index} stroke-width:3px"));
+            }
+        }
+        lines.join("\n") + "\n"
+    }
+
+    /// Graphviz DOT digraph, with `run` the visited nodes are filled
+    /// and the edges taken are drawn bold.
+    pub fn to_dot(&self, run: Option<&[NodeRun]>) -> String {
+        let run = run.map(RunAnnotation::new);
+        let mut lines = vec!["digraph flow {".to_owned(), "    rankdir=TB;".to_owned()];
+
+        for node in &self.nodes {
+            let mut attrs = vec![];
+            match run.as_ref().and_then(|r| r.status(node)) {
+                Some(status) => {
+                    attrs.push(format!(
+                        "label=\"{}\\n{}\"",
+                        escape_dot(node),
+                        escape_dot(status)
+                    ));
+                    let failed = run.as_ref().is_some_and(|r| r.failed(node));
+                    let color = if failed { "#f7d4d4" } else { "#d4f7d4" };
+                    attrs.push(format!("style=filled, fillcolor=\"{color}\""));
+                }
+                None => attrs.push(format!("label=\"{}\"", escape_dot(node))),
+            }
+            if *node == self.start {
+                attrs.push("peripheries=2".to_owned());
+            }
+            lines.push(format!(
+                "    \"{}\" [{}];",
+                escape_dot(node),
+                attrs.join(", ")
+            ));
+        }
+        for fan_out in &self.fan_outs {
+            lines.push(format!(
+                "    \"{}\" [shape=hexagon];",
+                escape_dot(&fan_out.name)
+            ));
+        }
+
+        for edge in &self.edges {
+            let mut attrs = vec![format!("label=\"{}\"", escape_dot(&edge.label))];
+            if run.as_ref().is_some_and(|r| r.took(edge)) {
+                attrs.push("penwidth=3".to_owned());
+            }
+            lines.push(format!(
+                "    \"{}\" -> \"{}\" [{}];",
+                escape_dot(&edge.from),
+                escape_dot(&edge.to),
+                attrs.join(", ")
+            ));
+        }
+        for fan_out in &self.fan_outs {
+            for branch in &fan_out.branches {
+                lines.push(format!(
+                    "    \"{}\" -> \"{}\" [style=dashed];",
+                    escape_dot(&fan_out.name),
+                    escape_dot(branch)
+                ));
+                lines.push(format!(
+                    "    \"{}\" -> \"{}\" [style=dashed];",
+                    escape_dot(branch),
+                    escape_dot(&fan_out.join)
+                ));
+            }
+        }
+        lines.push("}".to_owned());
+        lines.join("\n") + "\n"
+    }
+}
+
+// names of dangling edges have no id, make one that mermaid accepts
+fn escape_id(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn escape_mermaid(s: &str) -> String {
+    s.replace('"', "#quot;")
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::Value;
+
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::{
+        core::{context::Context, node::Node, status::Status},
+        flow,
+    };
+
+    #[derive(Debug, PartialEq, Status)]
+    enum MyStatus {
+        #[status(default)]
+        Written,
+        #[status(failed)]
+        Failed,
+    }
+    struct NoopNode {}
+    #[async_trait::async_trait]
+    impl Node for NoopNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, _context: &mut Context) -> anyhow::Result<Value> {
+            Ok(Value::Null)
+        }
+    }
+
+    fn node_run(node: &str, status: &str) -> NodeRun {
+        NodeRun {
+            node: node.to_owned(),
+            fan_out: None,
+            started_at: chrono::Utc::now(),
+            finished_at: chrono::Utc::now(),
+            status: Some(status.to_owned()),
+            failed: status == "Failed",
+            message: String::new(),
+            error: None,
+            retried: Vec::new(),
+            token_usage: 0,
+        }
+    }
+
+    #[test]
+    fn test_flow_graph_export() {
+        let graph = flow! {
+            start: ("writer", Arc::new(NoopNode {})),
+            nodes: [("editor", Arc::new(NoopNode {}))],
+            edges: [
+                ("writer", MyStatus::Written, "editor"),
+                ("writer", MyStatus::Failed, "writer"),
+            ]
+        }
+        .graph();
+        assert_eq!(graph.nodes, vec!["writer", "editor"]);
+
+        assert_eq!(
+            graph.to_mermaid(None),
+            r#"flowchart TD
+    n0["writer"]
+    n1["editor"]
+    n0 -->|"Written"| n1
+    n0 -->|"Failed"| n0
+"#
+        );
+
+        let run = [node_run("writer", "Written"), node_run("editor", "Failed")];
+        let mermaid = graph.to_mermaid(Some(&run));
+        assert!(mermaid.contains(r#"n0["writer<br/>Written"]"#));
+        assert!(mermaid.contains("class n0 visited"));
+        assert!(mermaid.contains("class n1 failed"));
+        assert!(mermaid.contains("linkStyle 0 stroke-width:3px"));
+
+        let dot = graph.to_dot(Some(&run));
+        assert!(dot.starts_with("digraph flow {"));
+        assert!(dot.contains(r#""writer" -> "editor" [label="Written", penwidth=3];"#));
+        assert!(dot.contains(r#""writer" -> "writer" [label="Failed"];"#));
+        assert!(dot.contains(r##"fillcolor="#f7d4d4""##));
+    }
+}
@@ -1,6 +1,21 @@
 pub mod agent;
+pub mod batch;
+pub mod breakpoint;
+pub mod cache;
+pub mod checkpoint;
 pub mod context;
+pub mod edge;
+pub mod error;
+pub mod event_log;
+pub mod fan_out;
 pub mod flow;
+pub mod graph;
 pub mod node;
+pub mod observer;
+pub mod report;
+pub mod retry;
 pub mod status;
 pub mod stream_message;
+pub mod sub_flow;
+pub mod task;
+pub mod template;
@@ -1,7 +1,13 @@
+use std::{sync::Arc, time::Duration};
+
 use serde_json::Value;
 
 use super::{
+    cache::{CachePolicy, NodeCache, execute_cached},
     context::{CONTEXT_RESULT, Context},
+    error::FlowError,
+    observer::Observers,
+    retry::{RetryPolicy, execute_with_retry},
     status::Status,
 };
 
@@ -27,16 +33,174 @@ pub trait Node: Send + Sync {
                 context.set(CONTEXT_RESULT, value.clone());
                 Ok(NodeResult::default())
             }
-            Err(e) => Ok(NodeResult {
-                status: Self::FlowStatus::failed(),
-                message: e.to_string(),
-            }),
+            Err(e) => Ok(NodeResult::new(Self::FlowStatus::failed(), e.to_string())),
         }
     }
+
+    /// What the output depends on besides the context keys it reads, e.g. the prompt
+    /// and the model, part of the key when the node is cached by `Flow::set_cache`.
+    fn cache_config(&self) -> Value {
+        Value::Null
+    }
+
+    /// the type of the node, part of the cache key so that nodes of different types
+    /// never share their cached outputs
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Undo the side effects of a succeeded `execute`, e.g. delete a saved draft.
+    /// Called on the final context, latest node first, when a later node of the run
+    /// fails with an error that is not routed or the run stops with an error.
+    #[allow(unused_variables)]
+    async fn compensate(&self, context: &mut Context) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// prepare -> execute -> after_exec of one node
+pub(crate) async fn run_node<S: Status>(
+    node: &dyn Node<FlowStatus = S>,
+    context: &mut Context,
+) -> anyhow::Result<NodeResult<S>> {
+    run_node_traced(node, context, None, None, None, &[])
+        .await
+        .0
+}
+
+/// What happened inside [`run_node_traced`] besides its result
+#[derive(Debug, Default)]
+pub(crate) struct NodeTrace {
+    /// the error of the last `execute`, `after_exec` may have turned it into a status
+    pub exec_error: Option<String>,
+    /// the errors of the attempts that were retried
+    pub retried: Vec<String>,
+}
+
+/// [`run_node`] with an optional retry policy and timeout (of each attempt) for `execute`,
+/// an optional cache of its output, wrapped by the hooks of `observers`
+pub(crate) async fn run_node_traced<S: Status>(
+    node: &dyn Node<FlowStatus = S>,
+    context: &mut Context,
+    retry: Option<&RetryPolicy>,
+    timeout: Option<Duration>,
+    cache: Option<(&NodeCache, &CachePolicy)>,
+    observers: &Observers<S>,
+) -> (anyhow::Result<NodeResult<S>>, NodeTrace) {
+    let name = context.node().unwrap_or_default().to_owned();
+    // the observers whose before hooks ran, only they get the after hooks
+    let mut entered = observers;
+    // pre:
+    let mut prepared = Ok(());
+    for (i, observer) in observers.iter().enumerate() {
+        prepared = observer.before_prepare(&name, context).await;
+        if prepared.is_err() {
+            entered = &observers[..=i];
+            break;
+        }
+    }
+    if prepared.is_ok() {
+        prepared = node.prepare(context).await;
+    }
+    if let Err(e) = prepared {
+        // nothing is executed, the observers still see the node fail
+        let mut node_result = Ok(NodeResult::failure(e));
+        for observer in entered.iter().rev() {
+            observer.after_exec(&name, context, &mut node_result).await;
+        }
+        return (node_result, NodeTrace::default());
+    }
+    // exec:
+    let mut short_circuit = None;
+    for (i, observer) in observers.iter().enumerate() {
+        short_circuit = observer.before_execute(&name, context).await;
+        if short_circuit.is_some() {
+            entered = &observers[..=i];
+            break;
+        }
+    }
+    let (mut result, retried) = match (short_circuit, cache) {
+        (Some(result), _) => (result, Vec::new()),
+        (None, Some(cache)) => execute_cached(node, context, cache, retry, timeout).await,
+        (None, None) => execute_retried(node, context, retry, timeout).await,
+    };
+    for observer in entered.iter().rev() {
+        observer.after_execute(&name, context, &mut result).await;
+    }
+    let trace = NodeTrace {
+        exec_error: result.as_ref().err().map(ToString::to_string),
+        retried,
+    };
+
+    // after_exec:
+    let mut node_result = node.after_exec(context, &result).await;
+    if let (Ok(failure), Err(e)) = (&mut node_result, result)
+        && failure.status == S::failed()
+        && failure.error.is_none()
+    {
+        failure.error = Some(Arc::new(e));
+    }
+    for observer in entered.iter().rev() {
+        observer.after_exec(&name, context, &mut node_result).await;
+    }
+    (node_result, trace)
+}
+
+/// `execute`, retried by `retry` if there is one
+pub(crate) async fn execute_retried<S: Status>(
+    node: &dyn Node<FlowStatus = S>,
+    context: &mut Context,
+    retry: Option<&RetryPolicy>,
+    timeout: Option<Duration>,
+) -> (anyhow::Result<Value>, Vec<String>) {
+    match retry {
+        Some(policy) => execute_with_retry(node, context, policy, timeout).await,
+        None => (execute_within(node, context, timeout).await, Vec::new()),
+    }
+}
+
+/// `execute`, failed with `FlowError::Timeout` if it takes longer than `timeout`
+pub(crate) async fn execute_within<S: Status>(
+    node: &dyn Node<FlowStatus = S>,
+    context: &mut Context,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Value> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, node.execute(context))
+            .await
+            .unwrap_or_else(|_| Err(FlowError::Timeout(timeout).into())),
+        None => node.execute(context).await,
+    }
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct NodeResult<S: Status> {
     pub status: S,
     pub message: String,
+    /// the error the node failed with, set by the flow for a failed status
+    pub(crate) error: Option<Arc<anyhow::Error>>,
+}
+
+impl<S: Status> NodeResult<S> {
+    pub fn new(status: S, message: impl Into<String>) -> Self {
+        NodeResult {
+            status,
+            message: message.into(),
+            error: None,
+        }
+    }
+
+    /// the failed status carrying `error`, e.g. of `prepare`
+    pub fn failure(error: anyhow::Error) -> Self {
+        NodeResult {
+            status: S::failed(),
+            message: error.to_string(),
+            error: Some(Arc::new(error)),
+        }
+    }
+
+    /// the error the node failed with, `None` for the nodes that returned their status
+    pub fn error(&self) -> Option<&anyhow::Error> {
+        self.error.as_deref()
+    }
 }
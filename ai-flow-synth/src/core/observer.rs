@@ -0,0 +1,328 @@
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use super::{context::Context, node::NodeResult, status::Status};
+
+/// Hooks around every node of a flow run, for logging, metrics, auditing or permission
+/// checks without touching the nodes. Added by `Flow::with_observer`.
+///
+/// Observers compose like middleware: the `before_*` hooks are called in the order
+/// the observers were added, the `after_*` hooks and `on_edge` in reverse order,
+/// so the first observer wraps all the others. An observer that fails `before_prepare` or
+/// short-circuits `before_execute` is the innermost one for that node, the observers after it
+/// get no hooks for that node.
+#[async_trait::async_trait]
+#[allow(unused_variables)]
+pub trait FlowObserver<S: Status>: Send + Sync {
+    /// an error fails the node like an error of its `prepare`
+    async fn before_prepare(&self, node: &str, context: &mut Context) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// `Some(result)` short-circuits `execute` (and its retries), `after_exec` of the node
+    /// gets `result` instead, e.g. an `Err` to deny the node. The later observers are skipped.
+    async fn before_execute(
+        &self,
+        node: &str,
+        context: &mut Context,
+    ) -> Option<anyhow::Result<Value>> {
+        None
+    }
+
+    /// called with the result of `execute` before the node's `after_exec` sees it
+    async fn after_execute(
+        &self,
+        node: &str,
+        context: &mut Context,
+        result: &mut anyhow::Result<Value>,
+    ) {
+    }
+
+    /// called with the result of the node's `after_exec`, or with the failure of
+    /// `before_prepare` or `prepare`
+    async fn after_exec(
+        &self,
+        node: &str,
+        context: &mut Context,
+        result: &mut anyhow::Result<NodeResult<S>>,
+    ) {
+    }
+
+    /// `next` is the target of the selected edge, `None` ends the run.
+    /// Not called for the branches of a fan-out.
+    async fn on_edge(
+        &self,
+        node: &str,
+        result: &NodeResult<S>,
+        context: &mut Context,
+        next: &mut Option<String>,
+    ) {
+    }
+}
+
+pub(crate) type Observers<S> = [Arc<dyn FlowObserver<S>>];
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::{core::node::Node, flow};
+    use std::sync::Mutex;
+
+    #[derive(Debug, PartialEq, Status)]
+    enum MyStatus {
+        #[status(default)]
+        Done,
+        #[status(failed)]
+        Failed,
+    }
+
+    struct NameNode {}
+    #[async_trait::async_trait]
+    impl Node for NameNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            Ok(Value::from(context.node().unwrap_or_default()))
+        }
+    }
+
+    /// records the hooks, tagged by `name`
+    struct Audit {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+    #[async_trait::async_trait]
+    impl FlowObserver<MyStatus> for Audit {
+        async fn before_execute(
+            &self,
+            node: &str,
+            _context: &mut Context,
+        ) -> Option<anyhow::Result<Value>> {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push(format!("{} before {node}", self.name));
+            None
+        }
+
+        async fn after_exec(
+            &self,
+            node: &str,
+            _context: &mut Context,
+            result: &mut anyhow::Result<NodeResult<MyStatus>>,
+        ) {
+            let status = &result.as_ref().unwrap().status;
+            let mut calls = self.calls.lock().unwrap();
+            calls.push(format!("{} after {node} {status:?}", self.name));
+        }
+    }
+
+    /// denies `editor` and sends `reviewer` to `writer` instead of ending the run
+    struct Permission {}
+    #[async_trait::async_trait]
+    impl FlowObserver<MyStatus> for Permission {
+        async fn before_execute(
+            &self,
+            node: &str,
+            _context: &mut Context,
+        ) -> Option<anyhow::Result<Value>> {
+            (node == "editor").then(|| Err(anyhow::anyhow!("editor is not allowed")))
+        }
+
+        async fn on_edge(
+            &self,
+            node: &str,
+            _result: &NodeResult<MyStatus>,
+            context: &mut Context,
+            next: &mut Option<String>,
+        ) {
+            if node == "reviewer" && context.get("reviewed").is_none() {
+                context.set("reviewed", Value::Bool(true));
+                *next = Some("writer".to_owned());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_observers() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let audit = |name| {
+            Arc::new(Audit {
+                name,
+                calls: calls.clone(),
+            })
+        };
+        let f = flow! {
+            start: ("writer", Arc::new(NameNode {})),
+            nodes: [("editor", Arc::new(NameNode {})), ("reviewer", Arc::new(NameNode {}))],
+            edges: [
+                ("writer", MyStatus::Done, "editor"),
+                ("editor", MyStatus::Failed, "reviewer"),
+            ]
+        }
+        .with_observer(audit("outer"))
+        .with_observer(Arc::new(Permission {}))
+        .with_observer(audit("inner"));
+
+        let result = f.run(Context::new()).await.unwrap();
+        assert_eq!(result, Value::from("reviewer"));
+        let round = [
+            "outer before writer",
+            "inner before writer",
+            "inner after writer Done",
+            "outer after writer Done",
+            // denied, `inner` is not asked nor told
+            "outer before editor",
+            "outer after editor Failed",
+            "outer before reviewer",
+            "inner before reviewer",
+            "inner after reviewer Done",
+            "outer after reviewer Done",
+        ];
+        // the reviewer went back to the writer once
+        assert_eq!(*calls.lock().unwrap(), [round, round].concat());
+    }
+
+    /// fails in `prepare`
+    struct BrokenNode {}
+    #[async_trait::async_trait]
+    impl Node for BrokenNode {
+        type FlowStatus = MyStatus;
+
+        async fn prepare(&self, _context: &mut Context) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("broken"))
+        }
+
+        async fn execute(&self, _context: &mut Context) -> anyhow::Result<Value> {
+            Ok(Value::Null)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_observers_prepare_error() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let f = flow! {
+            start: ("broken", Arc::new(BrokenNode {})),
+            nodes: [("writer", Arc::new(NameNode {}))],
+            edges: [("broken", MyStatus::Failed, "writer")]
+        }
+        .with_observer(Arc::new(Audit {
+            name: "audit",
+            calls: calls.clone(),
+        }));
+
+        let result = f.run(Context::new()).await.unwrap();
+        assert_eq!(result, Value::from("writer"));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "audit after broken Failed",
+                "audit before writer",
+                "audit after writer Done",
+            ]
+        );
+    }
+
+    /// records all the node hooks, fails `before_prepare` or short-circuits `before_execute`
+    /// of the node `stop` (if any)
+    struct Gate {
+        name: &'static str,
+        stop: Option<(&'static str, bool)>, // (node, in before_prepare)
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+    impl Gate {
+        fn record(&self, hook: &str, node: &str) {
+            let call = format!("{} {hook} {node}", self.name);
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+    #[async_trait::async_trait]
+    impl FlowObserver<MyStatus> for Gate {
+        async fn before_prepare(&self, node: &str, _context: &mut Context) -> anyhow::Result<()> {
+            self.record("before_prepare", node);
+            match self.stop {
+                Some((stop, true)) if stop == node => Err(anyhow::anyhow!("not prepared")),
+                _ => Ok(()),
+            }
+        }
+
+        async fn before_execute(
+            &self,
+            node: &str,
+            _context: &mut Context,
+        ) -> Option<anyhow::Result<Value>> {
+            self.record("before_execute", node);
+            match self.stop {
+                Some((stop, false)) if stop == node => Some(Ok(Value::from("skipped"))),
+                _ => None,
+            }
+        }
+
+        async fn after_execute(
+            &self,
+            node: &str,
+            _context: &mut Context,
+            _result: &mut anyhow::Result<Value>,
+        ) {
+            self.record("after_execute", node);
+        }
+
+        async fn after_exec(
+            &self,
+            node: &str,
+            _context: &mut Context,
+            _result: &mut anyhow::Result<NodeResult<MyStatus>>,
+        ) {
+            self.record("after_exec", node);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_observers_short_circuit() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let gate = |name, stop| {
+            Arc::new(Gate {
+                name,
+                stop,
+                calls: calls.clone(),
+            })
+        };
+        let f = flow!(start: ("writer", Arc::new(NameNode {})))
+            .with_observer(gate("outer", None))
+            .with_observer(gate("cache", Some(("writer", false))))
+            .with_observer(gate("inner", None));
+        let result = f.run(Context::new()).await.unwrap();
+        assert_eq!(result, Value::from("skipped"));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "outer before_prepare writer",
+                "cache before_prepare writer",
+                "inner before_prepare writer",
+                "outer before_execute writer",
+                "cache before_execute writer",
+                // `inner` did not see the node execute
+                "cache after_execute writer",
+                "outer after_execute writer",
+                "cache after_exec writer",
+                "outer after_exec writer",
+            ]
+        );
+
+        calls.lock().unwrap().clear();
+        let f = flow!(start: ("writer", Arc::new(NameNode {})))
+            .with_observer(gate("outer", None))
+            .with_observer(gate("auth", Some(("writer", true))))
+            .with_observer(gate("inner", None));
+        f.run(Context::new()).await.unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "outer before_prepare writer",
+                "auth before_prepare writer",
+                "auth after_exec writer",
+                "outer after_exec writer",
+            ]
+        );
+    }
+}
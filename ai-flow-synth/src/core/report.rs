@@ -0,0 +1,110 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    error::FlowError,
+    node::{NodeResult, NodeTrace},
+    status::Status,
+};
+
+/// What one flow run did node by node, returned by `Flow::run_with_report`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowRunReport {
+    /// the id of the context the flow ran on
+    pub run_id: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// the executed nodes, in the order they finished
+    pub nodes: Vec<NodeRun>,
+    pub status: RunStatus,
+    /// the error the run stopped with
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Finished,
+    Failed,
+    /// stopped by `Context::cancel`
+    Cancelled,
+}
+
+impl RunStatus {
+    pub fn of<T>(result: &anyhow::Result<T>) -> Self {
+        match result {
+            Ok(_) => RunStatus::Finished,
+            Err(e) if matches!(e.downcast_ref(), Some(FlowError::Cancelled)) => {
+                RunStatus::Cancelled
+            }
+            Err(_) => RunStatus::Failed,
+        }
+    }
+}
+
+impl FlowRunReport {
+    /// tokens used by all nodes of the run
+    pub fn token_usage(&self) -> i64 {
+        self.nodes.iter().map(|node| node.token_usage).sum()
+    }
+}
+
+/// One execution of a node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeRun {
+    pub node: String,
+    /// the fan-out the node ran as a branch of
+    #[serde(default)]
+    pub fan_out: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// `Status::label` of the status, `None` if the node stopped the run before returning one
+    /// or the status has no label
+    pub status: Option<String>,
+    /// the node returned the failed status or stopped the run
+    #[serde(default)]
+    pub failed: bool,
+    pub message: String,
+    /// the error of `execute`, `prepare` or `after_exec`, or the one that stopped the run
+    pub error: Option<String>,
+    /// the errors of the attempts before the last one, see `Flow::set_retry`
+    #[serde(default)]
+    pub retried: Vec<String>,
+    /// tokens the node added with `Context::add_token_usage`
+    #[serde(default)]
+    pub token_usage: i64,
+}
+
+impl NodeRun {
+    pub(crate) fn finished<S: Status>(
+        node: &str,
+        started_at: DateTime<Utc>,
+        token_usage: i64,
+        result: &anyhow::Result<NodeResult<S>>,
+        trace: NodeTrace,
+    ) -> Self {
+        let (status, failed, message, error) = match result {
+            Ok(result) => (
+                result.status.label().map(str::to_owned),
+                result.status == S::failed(),
+                result.message.clone(),
+                trace
+                    .exec_error
+                    .or_else(|| result.error.as_ref().map(ToString::to_string)),
+            ),
+            Err(e) => (None, true, String::new(), Some(e.to_string())),
+        };
+        NodeRun {
+            node: node.to_owned(),
+            fan_out: None,
+            started_at,
+            finished_at: Utc::now(),
+            status,
+            failed,
+            message,
+            error,
+            retried: trace.retried,
+            token_usage,
+        }
+    }
+}
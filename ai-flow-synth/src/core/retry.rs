@@ -0,0 +1,194 @@
+use std::{sync::Arc, time::Duration};
+
+use serde_json::Value;
+
+use super::{
+    context::Context,
+    error::FlowError,
+    node::{Node, execute_within},
+    status::Status,
+    stream_message::StreamMessage,
+};
+
+pub type RetryPredicate = Arc<dyn Fn(&anyhow::Error) -> bool + Send + Sync>;
+
+/// the stream of the "retrying n/m" procedures
+pub static RETRY_STREAM: &str = "retry";
+
+/// How often a failed `Node::execute` is tried again, see `Flow::set_retry`.
+///
+/// The delay before attempt `n + 1` is `initial_backoff * multiplier^(n - 1)`, capped by
+/// `max_backoff`, then reduced by a random part of up to `jitter` of it.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// attempts in total, including the first one
+    pub max_attempts: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// `0.0..=1.0`, the share of the delay that is randomized
+    pub jitter: f64,
+    retryable: RetryPredicate,
+}
+
+impl std::fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("multiplier", &self.multiplier)
+            .field("jitter", &self.jitter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.2,
+            retryable: Arc::new(|_| true),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: usize) -> Self {
+        RetryPolicy {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Fails if `multiplier` is negative or not finite.
+    pub fn with_multiplier(mut self, multiplier: f64) -> Result<Self, FlowError> {
+        self.multiplier = checked("multiplier", multiplier)?;
+        Ok(self)
+    }
+
+    /// Fails if `jitter` is negative or not finite, a jitter above 1.0 is clamped to it.
+    pub fn with_jitter(mut self, jitter: f64) -> Result<Self, FlowError> {
+        self.jitter = checked("jitter", jitter)?.min(1.0);
+        Ok(self)
+    }
+
+    /// only retry the errors `f` returns true for, all errors are retried by default
+    pub fn retry_if(mut self, f: impl Fn(&anyhow::Error) -> bool + Send + Sync + 'static) -> Self {
+        self.retryable = Arc::new(f);
+        self
+    }
+
+    pub fn is_retryable(&self, error: &anyhow::Error) -> bool {
+        (self.retryable)(error)
+    }
+
+    /// the delay after the failed attempt `attempt` (starting at 1)
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as usize) as i32;
+        // in f64 seconds, capped before it becomes a `Duration` that could overflow
+        let delay = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay = delay.min(self.max_backoff.as_secs_f64()).max(0.0);
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::random::<f64>();
+        Duration::try_from_secs_f64(delay * (1.0 - jitter)).unwrap_or(self.max_backoff)
+    }
+}
+
+fn checked(field: &str, value: f64) -> Result<f64, FlowError> {
+    if !value.is_finite() || value < 0.0 {
+        return Err(FlowError::Retry(format!(
+            "{field} must be finite and not negative, got {value}"
+        )));
+    }
+    Ok(value)
+}
+
+/// `execute` until it succeeds, fails with an error that is not retryable,
+/// or `policy.max_attempts` is reached. Returns the errors of the retried attempts too.
+pub(crate) async fn execute_with_retry<S: Status>(
+    node: &dyn Node<FlowStatus = S>,
+    context: &mut Context,
+    policy: &RetryPolicy,
+    timeout: Option<Duration>,
+) -> (anyhow::Result<Value>, Vec<String>) {
+    let mut retried = Vec::new();
+    let mut attempt = 1;
+    loop {
+        let result = execute_within(node, context, timeout).await;
+        let Err(e) = &result else {
+            return (result, retried);
+        };
+        if attempt >= policy.max_attempts || !policy.is_retryable(e) {
+            return (result, retried);
+        }
+        tracing::warn!("attempt {attempt}/{} failed: {e}", policy.max_attempts);
+        retried.push(e.to_string());
+        attempt += 1;
+        context
+            .stream(RETRY_STREAM)
+            .send(StreamMessage::Procedure(format!(
+                "retrying {attempt}/{}",
+                policy.max_attempts
+            )));
+        tokio::time::sleep(policy.backoff(attempt - 1)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy::new(5)
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(300))
+            .with_jitter(0.0)
+            .unwrap();
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(300));
+
+        let policy = policy.with_jitter(0.5).unwrap();
+        for attempt in 1..5 {
+            let delay = policy.backoff(attempt);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(300));
+        }
+    }
+
+    #[test]
+    fn test_retry_backoff_overflow() {
+        let policy = RetryPolicy::new(usize::MAX)
+            .with_backoff(Duration::from_secs(1), Duration::from_secs(60))
+            .with_jitter(0.0)
+            .unwrap();
+        for attempt in [64, 1_000, 100_000, usize::MAX] {
+            assert_eq!(policy.backoff(attempt), Duration::from_secs(60));
+        }
+        let policy = policy.with_multiplier(0.0).unwrap();
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(usize::MAX), Duration::ZERO);
+
+        // the fields are public, a bad value set on them still gives a valid delay
+        let mut policy = policy;
+        policy.multiplier = -2.0;
+        assert_eq!(policy.backoff(2), Duration::ZERO);
+        policy.multiplier = f64::NAN;
+        assert_eq!(policy.backoff(2), Duration::from_secs(60));
+
+        let invalid = RetryPolicy::new(3).with_multiplier(-1.0).unwrap_err();
+        assert!(matches!(invalid, FlowError::Retry(_)));
+        assert!(RetryPolicy::new(3).with_jitter(f64::INFINITY).is_err());
+        assert!(RetryPolicy::new(3).with_jitter(f64::NAN).is_err());
+        assert_eq!(RetryPolicy::new(3).with_jitter(2.0).unwrap().jitter, 1.0);
+    }
+}
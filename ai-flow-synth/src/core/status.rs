@@ -1,7 +1,100 @@
+/// `#[derive(Status)]` with `#[status(failed)]` and `#[status(default)]` on the variants,
+/// also derives `Display` and `FromStr` by the variant names
+pub use ai_flow_synth_derive::Status;
+
 /// The abstract trait to represent the status of a task
 /// usually use a enum
 /// The status like a simple state machine result
 pub trait Status: Send + Sync + Default + PartialEq {
     // the task is failed
     fn failed() -> Self;
+
+    /// the name of the status in run reports and flow graphs, `#[derive(Status)]`
+    /// returns [`StatusNames::name`]
+    fn label(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// The names of the statuses of a `#[derive(Status)]` enum, the variant names
+pub trait StatusNames {
+    /// the names of all statuses, in declaration order
+    const NAMES: &'static [&'static str];
+
+    fn name(&self) -> &'static str;
+}
+
+/// The error of `FromStr` of a `#[derive(Status)]` enum
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("ParseStatusError: unknown status '{0}'")]
+pub struct ParseStatusError(pub String);
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Status)]
+    enum JobStatus {
+        NotStarted,
+        #[status(default)]
+        Written,
+        #[status(failed)]
+        Failed,
+    }
+
+    /// a hand-written status, without `Debug`
+    #[derive(Default, PartialEq)]
+    struct Plain;
+    impl Status for Plain {
+        fn failed() -> Self {
+            Plain
+        }
+    }
+
+    #[test]
+    fn test_derive_status() {
+        assert_eq!(JobStatus::failed(), JobStatus::Failed);
+        assert_eq!(JobStatus::default(), JobStatus::Written);
+        assert_eq!(Plain::failed().label(), None);
+        assert_eq!(JobStatus::NAMES, ["NotStarted", "Written", "Failed"]);
+        assert_eq!(JobStatus::NotStarted.to_string(), "NotStarted");
+        assert_eq!("Failed".parse(), Ok(JobStatus::Failed));
+        assert_eq!(
+            "Done".parse::<JobStatus>(),
+            Err(ParseStatusError("Done".to_owned()))
+        );
+        for name in JobStatus::NAMES {
+            let status: JobStatus = name.parse().unwrap();
+            assert_eq!(status.name(), *name);
+            assert_eq!(status.label(), Some(*name));
+            assert_eq!(format!("{status:?}"), *name);
+        }
+    }
+
+    /// has its own `name` and `NAMES`, one variant is a raw identifier
+    #[derive(Debug, Clone, PartialEq, Status)]
+    enum StepStatus {
+        #[status(default)]
+        r#Move,
+        #[status(failed)]
+        Failed,
+    }
+    impl StepStatus {
+        const NAMES: [&str; 1] = ["step"];
+
+        fn name(&self) -> String {
+            format!("step {self}")
+        }
+    }
+
+    #[test]
+    fn test_derive_status_names() {
+        assert_eq!(<StepStatus as StatusNames>::NAMES, ["Move", "Failed"]);
+        assert_eq!(StepStatus::NAMES, ["step"]);
+        assert_eq!(StepStatus::Move.name(), "step Move");
+        assert_eq!(StatusNames::name(&StepStatus::Move), "Move");
+        assert_eq!("Move".parse(), Ok(StepStatus::Move));
+        assert!("r#Move".parse::<StepStatus>().is_err());
+    }
 }
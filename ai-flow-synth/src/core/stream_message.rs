@@ -1,12 +1,126 @@
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use super::{event_log::EventSink, report::RunStatus};
+
+/// the stream the flow sends its node and run events to
+pub static FLOW_STREAM: &str = "flow";
+
+/// What the nodes, the flow and `llm::chat` stream to the client.
+///
+/// The wire format is externally tagged and stable, new events only add new tags:
+/// `{"d": "text"}`, `{"node_finished": {"node": "editor", "status": "Done", "message": ""}}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum StreamMessage {
+    /// a piece of the content
     #[serde(rename = "d")]
     Delta(String),
 
+    /// a progress note, like "retrying 2/3"
     #[serde(rename = "p")]
     Procedure(String),
+
+    /// a piece of the reasoning before the content
+    #[serde(rename = "thinking")]
+    Thinking(String),
+
+    #[serde(rename = "node_started")]
+    NodeStarted { node: String },
+
+    /// `status` is the `Debug` of the node status, `None` if the node stopped the run
+    #[serde(rename = "node_finished")]
+    NodeFinished {
+        node: String,
+        status: Option<String>,
+        message: String,
+    },
+
+    #[serde(rename = "tool_call")]
+    ToolCall {
+        id: String,
+        name: String,
+        arguments: Value,
+    },
+
+    #[serde(rename = "tool_result")]
+    ToolResult {
+        id: String,
+        name: String,
+        result: Value,
+    },
+
+    /// tokens the provider reported for one llm call
+    #[serde(rename = "usage")]
+    Usage { total_tokens: i64 },
+
+    #[serde(rename = "error")]
+    Error { message: String },
+
+    #[serde(rename = "flow_finished")]
+    FlowFinished {
+        status: RunStatus,
+        error: Option<String>,
+    },
+}
+
+impl StreamMessage {
+    /// the wire tag, e.g. for the `event:` field of server-sent events
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            StreamMessage::Delta(_) => "d",
+            StreamMessage::Procedure(_) => "p",
+            StreamMessage::Thinking(_) => "thinking",
+            StreamMessage::NodeStarted { .. } => "node_started",
+            StreamMessage::NodeFinished { .. } => "node_finished",
+            StreamMessage::ToolCall { .. } => "tool_call",
+            StreamMessage::ToolResult { .. } => "tool_result",
+            StreamMessage::Usage { .. } => "usage",
+            StreamMessage::Error { .. } => "error",
+            StreamMessage::FlowFinished { .. } => "flow_finished",
+        }
+    }
+}
+
+/// A [`StreamMessage`] with the stream and node it was sent from, see `Context::listen`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaggedMessage {
+    /// the id passed to `Context::stream`
+    pub stream: String,
+    /// the node the flow was running when it was sent
+    pub node: Option<String>,
+    /// the position in the context's `EventLog`, over all its streams
+    pub seq: u64,
+    pub message: StreamMessage,
+}
+
+/// Sends into one named stream of a `Context`, get it from `Context::stream`.
+#[derive(Debug, Clone)]
+pub struct StreamSender {
+    sink: Arc<EventSink>,
+    stream: String,
+    node: Option<String>,
+}
+
+impl StreamSender {
+    pub(crate) fn new(sink: Arc<EventSink>, stream: &str, node: Option<String>) -> Self {
+        StreamSender {
+            sink,
+            stream: stream.to_owned(),
+            node,
+        }
+    }
+
+    pub fn stream_id(&self) -> &str {
+        &self.stream
+    }
+
+    /// Append the message to the context's log, returns its seq.
+    /// Nobody listening is fine, a later listener replays it.
+    pub fn send(&self, message: StreamMessage) -> u64 {
+        self.sink.append(&self.stream, self.node.clone(), message)
+    }
 }
 
 #[cfg(test)]
@@ -20,4 +134,70 @@ mod tests {
         let serialized = serde_json::to_string(&message).unwrap();
         assert_eq!(serialized, r#"{"d":"test"}"#,);
     }
+
+    #[test]
+    fn test_serde_wire_format() {
+        let cases = [
+            (StreamMessage::Procedure("p".to_owned()), r#"{"p":"p"}"#),
+            (
+                StreamMessage::Thinking("hmm".to_owned()),
+                r#"{"thinking":"hmm"}"#,
+            ),
+            (
+                StreamMessage::NodeStarted {
+                    node: "writer".to_owned(),
+                },
+                r#"{"node_started":{"node":"writer"}}"#,
+            ),
+            (
+                StreamMessage::NodeFinished {
+                    node: "writer".to_owned(),
+                    status: Some("Written".to_owned()),
+                    message: String::new(),
+                },
+                r#"{"node_finished":{"node":"writer","status":"Written","message":""}}"#,
+            ),
+            (
+                StreamMessage::ToolCall {
+                    id: "call_0".to_owned(),
+                    name: "get_weather".to_owned(),
+                    arguments: serde_json::json!({ "location": "HangZhou" }),
+                },
+                r#"{"tool_call":{"id":"call_0","name":"get_weather","arguments":{"location":"HangZhou"}}}"#,
+            ),
+            (
+                StreamMessage::ToolResult {
+                    id: "call_0".to_owned(),
+                    name: "get_weather".to_owned(),
+                    result: Value::from("sunny"),
+                },
+                r#"{"tool_result":{"id":"call_0","name":"get_weather","result":"sunny"}}"#,
+            ),
+            (
+                StreamMessage::Usage { total_tokens: 42 },
+                r#"{"usage":{"total_tokens":42}}"#,
+            ),
+            (
+                StreamMessage::Error {
+                    message: "boom".to_owned(),
+                },
+                r#"{"error":{"message":"boom"}}"#,
+            ),
+            (
+                StreamMessage::FlowFinished {
+                    status: RunStatus::Cancelled,
+                    error: None,
+                },
+                r#"{"flow_finished":{"status":"cancelled","error":null}}"#,
+            ),
+        ];
+        for (message, wire) in cases {
+            assert_eq!(serde_json::to_string(&message).unwrap(), wire);
+            assert_eq!(
+                serde_json::from_str::<StreamMessage>(wire).unwrap(),
+                message
+            );
+            assert!(wire.starts_with(&format!("{{\"{}\"", message.event_name())));
+        }
+    }
 }
@@ -0,0 +1,341 @@
+use serde_json::Value;
+
+use super::{
+    context::{CONTEXT_RESULT, Context},
+    flow::Flow,
+    node::{Node, NodeResult},
+    status::Status,
+};
+
+/// How a sub-flow sees the context of its parent flow
+#[derive(Debug, Clone, Default)]
+pub enum ContextMode {
+    /// the sub-flow reads and writes the parent context directly
+    #[default]
+    Shared,
+    /// the sub-flow runs on a fresh context (same stream),
+    /// only `inputs` are copied in and only `outputs` are copied back
+    Isolated {
+        inputs: Vec<String>,
+        outputs: Vec<String>,
+    },
+}
+
+/// Wrap a `Flow<S>` to use it as a node of a `Flow<P>`.
+///
+/// The status of the last node the sub-flow executed is mapped to the parent status
+/// by `map_status`, unmapped statuses become `P::failed()` if they are `S::failed()`,
+/// otherwise `P::default()`. `execute` returns `{"status": ..., "result": ...}`
+/// to hand the mapped status to `after_exec`, which writes only `result` to the context.
+///
+/// The sub-flow runs within what is left of the parent run's budget. A shared sub-flow
+/// saves its checkpoints as run `<parent run id>/<node name>`, an isolated one
+/// as the run of its own context.
+pub struct SubFlow<S: Status, P: Status> {
+    flow: Flow<S>,
+    status_map: Vec<(S, P)>,
+    mode: ContextMode,
+}
+
+impl<S: Status, P: Status> SubFlow<S, P> {
+    pub fn new(flow: Flow<S>) -> Self {
+        SubFlow {
+            flow,
+            status_map: Vec::new(),
+            mode: ContextMode::default(),
+        }
+    }
+
+    pub fn map_status(mut self, from: S, to: P) -> Self {
+        self.status_map.push((from, to));
+        self
+    }
+
+    pub fn isolated(mut self, inputs: &[&str], outputs: &[&str]) -> Self {
+        self.mode = ContextMode::Isolated {
+            inputs: inputs.iter().map(|k| k.to_string()).collect(),
+            outputs: outputs.iter().map(|k| k.to_string()).collect(),
+        };
+        self
+    }
+
+    fn mapped_index(&self, status: Option<S>) -> Option<usize> {
+        let status = status?;
+        self.status_map.iter().position(|(from, _)| *from == status)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Status + 'static, P: Status + Clone + 'static> Node for SubFlow<S, P> {
+    type FlowStatus = P;
+
+    async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+        let (status, result) = match &self.mode {
+            ContextMode::Shared => {
+                let node = context.node().map(str::to_owned);
+                let run_id = format!("{}/{}", context.id(), node.as_deref().unwrap_or_default());
+                let status = self.flow.run_in(context, &run_id).await;
+                context.set_node(node);
+                let status = status?;
+                let result = context.get(CONTEXT_RESULT).cloned().unwrap_or_default();
+                (status, result)
+            }
+            ContextMode::Isolated { inputs, outputs } => {
+                let mut sub_context = context.fork();
+                for key in inputs {
+                    if let Some(value) = context.get(key) {
+                        sub_context.set(key, value.clone());
+                    }
+                }
+                let run_id = sub_context.id().to_owned();
+                let status = self.flow.run_in(&mut sub_context, &run_id).await;
+                context.add_token_usage(sub_context.token_usage());
+                context.set_budget(sub_context.budget().cloned());
+                let status = status?;
+                for key in outputs {
+                    if let Some(value) = sub_context.get(key) {
+                        context.set(key, value.clone());
+                    }
+                }
+                let result = sub_context.get(CONTEXT_RESULT).cloned().unwrap_or_default();
+                (status, result)
+            }
+        };
+
+        let failed = status.as_ref().is_some_and(|s| *s == S::failed());
+        let mapped = match self.mapped_index(status) {
+            Some(index) => Value::from(index),
+            None if failed => Value::from("failed"),
+            None => Value::Null,
+        };
+        Ok(serde_json::json!({ "status": mapped, "result": result }))
+    }
+
+    async fn after_exec(
+        &self,
+        context: &mut Context,
+        result: &anyhow::Result<Value>,
+    ) -> anyhow::Result<NodeResult<Self::FlowStatus>> {
+        match result {
+            Ok(value) => {
+                let result = value.get("result").cloned().unwrap_or_default();
+                context.set(CONTEXT_RESULT, result);
+                let status = match value.get("status") {
+                    Some(Value::Number(index)) => index
+                        .as_u64()
+                        .and_then(|i| self.status_map.get(i as usize))
+                        .map(|(_, to)| to.clone())
+                        .unwrap_or_default(),
+                    Some(Value::String(_)) => P::failed(),
+                    _ => P::default(),
+                };
+                Ok(NodeResult::new(status, String::new()))
+            }
+            Err(e) => Ok(NodeResult::new(P::failed(), e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::{
+        core::{
+            checkpoint::CheckpointStore,
+            error::{BudgetExceeded, FlowError},
+            flow::FlowBudget,
+        },
+        flow,
+    };
+
+    #[derive(Debug, PartialEq, Status)]
+    enum WriteStatus {
+        #[status(default)]
+        Written,
+        #[status(failed)]
+        Failed,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Status)]
+    enum JobStatus {
+        #[status(default)]
+        NotStarted,
+        Drafted,
+        #[status(failed)]
+        Failed,
+    }
+
+    struct DraftNode {}
+    #[async_trait::async_trait]
+    impl Node for DraftNode {
+        type FlowStatus = WriteStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            let topic = context.get("topic").and_then(Value::as_str).unwrap_or("");
+            let draft = format!("draft about {topic}");
+            context.set("draft", Value::from(draft.clone()));
+            context.set("scratch", Value::from("notes"));
+            Ok(Value::from(draft))
+        }
+    }
+
+    fn writer_flow() -> Flow<WriteStatus> {
+        flow!(start: ("draft", Arc::new(DraftNode {})))
+    }
+
+    #[tokio::test]
+    async fn test_sub_flow() {
+        let sub_flow =
+            SubFlow::new(writer_flow()).map_status(WriteStatus::Written, JobStatus::Drafted);
+        let mut context = Context::new();
+        context.set("topic", Value::from("robots"));
+        let result = sub_flow.execute(&mut context).await;
+        let node_result = sub_flow.after_exec(&mut context, &result).await.unwrap();
+        assert_eq!(node_result.status, JobStatus::Drafted);
+        assert_eq!(context.get("scratch"), Some(&Value::from("notes")));
+        let mut keys = context.data().keys().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["draft", "result", "scratch", "topic"]);
+        assert_eq!(
+            context.get(CONTEXT_RESULT),
+            Some(&Value::from("draft about robots"))
+        );
+
+        let sub_flow = SubFlow::<_, JobStatus>::new(writer_flow()).isolated(&["topic"], &["draft"]);
+        let mut context = Context::new();
+        context.set("topic", Value::from("robots"));
+        let result = sub_flow.execute(&mut context).await;
+        let node_result = sub_flow.after_exec(&mut context, &result).await.unwrap();
+        assert_eq!(node_result.status, JobStatus::NotStarted);
+        assert_eq!(
+            context.get("draft"),
+            Some(&Value::from("draft about robots"))
+        );
+        assert_eq!(context.get("scratch"), None);
+    }
+
+    struct PublishNode {}
+    #[async_trait::async_trait]
+    impl Node for PublishNode {
+        type FlowStatus = JobStatus;
+
+        async fn execute(&self, _context: &mut Context) -> anyhow::Result<Value> {
+            Ok(Value::from("published"))
+        }
+    }
+
+    fn budget_error(result: anyhow::Result<Value>) -> BudgetExceeded {
+        match result.unwrap_err().downcast::<FlowError>().unwrap() {
+            FlowError::BudgetExceeded(exceeded) => exceeded,
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sub_flow_budget() {
+        // the sub-flow loops forever, only the parent's budget stops it
+        let looping = flow! {
+            start: ("draft", Arc::new(DraftNode {})),
+            nodes: [],
+            edges: [("draft", WriteStatus::Written, "draft")]
+        };
+        let f = flow!(start: ("job", Arc::new(SubFlow::<_, JobStatus>::new(looping))))
+            .with_budget(FlowBudget::default().with_max_steps(5));
+        let exceeded = budget_error(f.run(Context::new()).await);
+        assert!(matches!(exceeded, BudgetExceeded::MaxSteps(_)));
+
+        // the steps of the sub-flow count to the parent run
+        let writer = flow! {
+            start: ("draft", Arc::new(DraftNode {})),
+            nodes: [("review", Arc::new(DraftNode {}))],
+            edges: [("draft", WriteStatus::Written, "review")]
+        };
+        let sub_flow = SubFlow::new(writer).map_status(WriteStatus::Written, JobStatus::Drafted);
+        let f = flow! {
+            start: ("job", Arc::new(sub_flow)),
+            nodes: [("publish", Arc::new(PublishNode {}))],
+            edges: [("job", JobStatus::Drafted, "publish")]
+        };
+        let f = f.with_budget(FlowBudget::default().with_max_steps(4));
+        assert_eq!(
+            f.run(Context::new()).await.unwrap(),
+            Value::from("published")
+        );
+        let f = f.with_budget(FlowBudget::default().with_max_steps(3));
+        let exceeded = budget_error(f.run(Context::new()).await);
+        assert_eq!(exceeded, BudgetExceeded::MaxSteps(3));
+    }
+
+    /// stops the run the first time it is executed
+    struct ReviewNode {
+        reviewed: std::sync::atomic::AtomicBool,
+    }
+    #[async_trait::async_trait]
+    impl Node for ReviewNode {
+        type FlowStatus = WriteStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            if !self
+                .reviewed
+                .swap(true, std::sync::atomic::Ordering::SeqCst)
+            {
+                context.cancel();
+            }
+            Ok(Value::from("reviewed"))
+        }
+    }
+
+    struct PlanNode {}
+    #[async_trait::async_trait]
+    impl Node for PlanNode {
+        type FlowStatus = JobStatus;
+
+        async fn execute(&self, _context: &mut Context) -> anyhow::Result<Value> {
+            Ok(Value::from("planned"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sub_flow_checkpoint() {
+        let store = CheckpointStore::memory();
+        let writer = flow! {
+            start: ("review", Arc::new(ReviewNode { reviewed: Default::default() })),
+            nodes: [("draft", Arc::new(DraftNode {}))],
+            edges: [("review", WriteStatus::Written, "draft")]
+        }
+        .with_checkpoint(store.clone());
+        let sub_flow = SubFlow::new(writer).map_status(WriteStatus::Written, JobStatus::Drafted);
+        let f = flow! {
+            start: ("plan", Arc::new(PlanNode {})),
+            nodes: [("job", Arc::new(sub_flow)), ("publish", Arc::new(PublishNode {}))],
+            edges: [
+                ("plan", JobStatus::NotStarted, "job"),
+                ("job", JobStatus::Drafted, "publish"),
+            ]
+        }
+        .with_checkpoint(store.clone());
+
+        // the run stops within the sub-flow
+        let context = Context::new();
+        let run_id = context.id().to_owned();
+        let err = f.run(context).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlowError>(),
+            Some(FlowError::Cancelled)
+        ));
+        let checkpoint = store.load(&run_id).await.unwrap().unwrap();
+        assert_eq!(checkpoint.next_node.as_deref(), Some("job"));
+        let sub_run_id = format!("{run_id}/job");
+        let checkpoint = store.load(&sub_run_id).await.unwrap().unwrap();
+        assert_eq!(checkpoint.next_node.as_deref(), Some("draft"));
+
+        // the parent continues after the sub-flow finished
+        let result = f.resume(&run_id, Context::new()).await.unwrap();
+        assert_eq!(result, Value::from("published"));
+        assert!(store.load(&run_id).await.unwrap().is_none());
+        assert!(store.load(&sub_run_id).await.unwrap().is_none());
+    }
+}
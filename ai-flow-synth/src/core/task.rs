@@ -0,0 +1,477 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{Semaphore, watch};
+use tokio_util::sync::CancellationToken;
+
+use crate::utils::MongoClient;
+
+use super::{
+    breakpoint::PausedNode,
+    context::Context,
+    error::FlowError,
+    event_log::{EventLog, MessageStream},
+    flow::Flow,
+    report::{FlowRunReport, RunStatus},
+    status::Status,
+};
+
+pub static TASK_COLLECTION: &str = "flow_tasks";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    /// waiting for a free slot of the `TaskManager`
+    Queued,
+    Running,
+    /// stopped at a breakpoint until `TaskManager::resume`
+    Paused,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+impl TaskStatus {
+    /// the task will not change anymore
+    pub fn is_done(&self) -> bool {
+        matches!(
+            self,
+            TaskStatus::Finished | TaskStatus::Failed | TaskStatus::Cancelled
+        )
+    }
+}
+
+impl From<RunStatus> for TaskStatus {
+    fn from(status: RunStatus) -> Self {
+        match status {
+            RunStatus::Finished => TaskStatus::Finished,
+            RunStatus::Failed => TaskStatus::Failed,
+            RunStatus::Cancelled => TaskStatus::Cancelled,
+        }
+    }
+}
+
+/// What a `TaskManager` knows about one of its tasks, also what `TaskStore` saves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskInfo {
+    pub task_id: String,
+    /// the name the task was spawned with, e.g. the flow template
+    pub name: String,
+    /// the id of the context the flow runs on
+    pub run_id: String,
+    pub status: TaskStatus,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// the breakpoint node the task waits at
+    pub paused_at: Option<String>,
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    pub report: Option<FlowRunReport>,
+}
+
+/// Saves one document per task into mongo, the latest one replaces the previous.
+#[derive(Debug, Clone)]
+pub struct TaskStore {
+    collection: mongodb::Collection<TaskInfo>,
+}
+
+impl TaskStore {
+    pub fn new(client: &MongoClient) -> Self {
+        TaskStore {
+            collection: client.collection(TASK_COLLECTION),
+        }
+    }
+
+    pub async fn save(&self, task: &TaskInfo) -> anyhow::Result<()> {
+        self.collection
+            .replace_one(doc! { "task_id": &task.task_id }, task)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn load(&self, task_id: &str) -> anyhow::Result<Option<TaskInfo>> {
+        Ok(self
+            .collection
+            .find_one(doc! { "task_id": task_id })
+            .await?)
+    }
+}
+
+struct TaskEntry {
+    info: watch::Sender<TaskInfo>,
+    events: EventLog,
+    cancel: CancellationToken,
+    paused: Option<PausedNode>,
+}
+
+/// Runs flows as tasks, at most `max_concurrency` of them at once, the others are queued.
+/// Clones share the tasks.
+///
+/// A done task, with its event log, is kept until `remove` is called,
+/// or until its retention ends if the manager has one, see `with_retention`.
+#[derive(Clone)]
+pub struct TaskManager {
+    tasks: Arc<Mutex<HashMap<String, TaskEntry>>>,
+    permits: Arc<Semaphore>,
+    store: Option<TaskStore>,
+    retention: Option<Duration>,
+}
+
+impl TaskManager {
+    /// a `max_concurrency` of 0 is taken as 1
+    pub fn new(max_concurrency: usize) -> Self {
+        TaskManager {
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+            permits: Arc::new(Semaphore::new(max_concurrency.max(1))),
+            store: None,
+            retention: None,
+        }
+    }
+
+    /// save every status change of the tasks
+    pub fn with_store(mut self, store: TaskStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Remove a done task `retention` after it finished, the store keeps its info.
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Queue a run of `flow` on `context`, returns the task id.
+    /// The manager takes the breakpoint pauses of the context, see [`TaskManager::resume`].
+    pub fn spawn<S: Status + 'static>(
+        &self,
+        name: &str,
+        flow: Arc<Flow<S>>,
+        mut context: Context,
+    ) -> String {
+        let task_id = uuid::Uuid::new_v4().to_string();
+        let info = TaskInfo {
+            task_id: task_id.clone(),
+            name: name.to_owned(),
+            run_id: context.id().to_owned(),
+            status: TaskStatus::Queued,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            paused_at: None,
+            result: None,
+            error: None,
+            report: None,
+        };
+        let mut pauses = context.enable_breakpoints();
+        let cancel = context.cancellation_token().clone();
+        self.lock().insert(
+            task_id.clone(),
+            TaskEntry {
+                info: watch::channel(info).0,
+                events: context.event_log(),
+                cancel: cancel.clone(),
+                paused: None,
+            },
+        );
+
+        let manager = self.clone();
+        let id = task_id.clone();
+        tokio::spawn(async move {
+            manager.save(&id).await;
+            let permit = tokio::select! {
+                permit = manager.permits.clone().acquire_owned() => permit.ok(),
+                _ = cancel.cancelled() => None,
+            };
+            if permit.is_none() {
+                manager.update(&id, |info| {
+                    info.status = TaskStatus::Cancelled;
+                    info.finished_at = Some(Utc::now());
+                    info.error = Some(FlowError::Cancelled.to_string());
+                });
+                manager.save(&id).await;
+                manager.expire(&id).await;
+                return;
+            }
+
+            manager.update(&id, |info| {
+                info.status = TaskStatus::Running;
+                info.started_at = Some(Utc::now());
+            });
+            manager.save(&id).await;
+            let watch_pauses = {
+                let manager = manager.clone();
+                let id = id.clone();
+                tokio::spawn(async move {
+                    while let Some(paused) = pauses.recv().await {
+                        manager.pause(&id, paused).await;
+                    }
+                })
+            };
+
+            // on its own task, so a panicking node fails the task instead of leaving it running
+            let run = tokio::spawn(async move { flow.run_with_report(context).await });
+            let run = run.await;
+            watch_pauses.abort();
+            manager.update(&id, |info| {
+                info.paused_at = None;
+                match run {
+                    Ok((result, report)) => {
+                        info.status = report.status.into();
+                        info.finished_at = Some(report.finished_at);
+                        info.error = report.error.clone();
+                        info.result = result.ok();
+                        info.report = Some(report);
+                    }
+                    Err(e) => {
+                        info.status = TaskStatus::Failed;
+                        info.finished_at = Some(Utc::now());
+                        info.error = Some(format!("the run panicked: {e}"));
+                    }
+                }
+            });
+            if let Some(entry) = manager.lock().get_mut(&id) {
+                entry.paused = None;
+            }
+            manager.save(&id).await;
+            manager.expire(&id).await;
+        });
+        task_id
+    }
+
+    pub fn status(&self, task_id: &str) -> Option<TaskStatus> {
+        self.info(task_id).map(|info| info.status)
+    }
+
+    pub fn info(&self, task_id: &str) -> Option<TaskInfo> {
+        let tasks = self.lock();
+        Some(tasks.get(task_id)?.info.borrow().clone())
+    }
+
+    /// all tasks, oldest first
+    pub fn list(&self) -> Vec<TaskInfo> {
+        let mut tasks = self
+            .lock()
+            .values()
+            .map(|entry| entry.info.borrow().clone())
+            .collect::<Vec<_>>();
+        tasks.sort_by_key(|info| info.created_at);
+        tasks
+    }
+
+    /// Changes of the task's info, e.g. `wait_for(|info| info.status.is_done())`.
+    pub fn subscribe(&self, task_id: &str) -> Option<watch::Receiver<TaskInfo>> {
+        Some(self.lock().get(task_id)?.info.subscribe())
+    }
+
+    /// the info once the task is done
+    pub async fn wait(&self, task_id: &str) -> Option<TaskInfo> {
+        let mut info = self.subscribe(task_id)?;
+        let info = info.wait_for(|info| info.status.is_done()).await.ok()?;
+        Some(info.clone())
+    }
+
+    /// The messages of the task's run from the start, ends with the run.
+    pub fn listen(&self, task_id: &str) -> Option<MessageStream> {
+        Some(self.event_log(task_id)?.listen_from(0))
+    }
+
+    pub fn event_log(&self, task_id: &str) -> Option<EventLog> {
+        Some(self.lock().get(task_id)?.events.clone())
+    }
+
+    /// Cancel the task, queued or running. Returns false if it is unknown or already done.
+    pub fn stop(&self, task_id: &str) -> bool {
+        let tasks = self.lock();
+        match tasks.get(task_id) {
+            Some(entry) if !entry.info.borrow().status.is_done() => {
+                entry.cancel.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Continue a paused task, `edits` are set into its context first.
+    /// Returns false if the task is not paused.
+    pub async fn resume(&self, task_id: &str, edits: HashMap<String, Value>) -> bool {
+        let paused = {
+            let mut tasks = self.lock();
+            let Some(paused) = tasks.get_mut(task_id).and_then(|entry| entry.paused.take()) else {
+                return false;
+            };
+            paused
+        };
+        self.update(task_id, |info| {
+            info.status = TaskStatus::Running;
+            info.paused_at = None;
+        });
+        paused.resume_with(edits);
+        self.save(task_id).await;
+        true
+    }
+
+    /// Forget a done task, returns its last info. Tasks that are not done are kept.
+    pub fn remove(&self, task_id: &str) -> Option<TaskInfo> {
+        let mut tasks = self.lock();
+        if !tasks.get(task_id)?.info.borrow().status.is_done() {
+            return None;
+        }
+        let entry = tasks.remove(task_id)?;
+        Some(entry.info.borrow().clone())
+    }
+
+    async fn pause(&self, task_id: &str, paused: PausedNode) {
+        let node = paused.node.clone();
+        if let Some(entry) = self.lock().get_mut(task_id) {
+            entry.paused = Some(paused);
+        }
+        self.update(task_id, |info| {
+            info.status = TaskStatus::Paused;
+            info.paused_at = Some(node);
+        });
+        self.save(task_id).await;
+    }
+
+    /// remove the done task once the retention ended
+    async fn expire(&self, task_id: &str) {
+        if let Some(retention) = self.retention {
+            tokio::time::sleep(retention).await;
+            self.remove(task_id);
+        }
+    }
+
+    fn update(&self, task_id: &str, f: impl FnOnce(&mut TaskInfo)) {
+        if let Some(entry) = self.lock().get(task_id) {
+            entry.info.send_modify(f);
+        }
+    }
+
+    async fn save(&self, task_id: &str) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let Some(info) = self.info(task_id) else {
+            return;
+        };
+        if let Err(e) = store.save(&info).await {
+            tracing::warn!("failed to save task '{task_id}': {e}");
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, TaskEntry>> {
+        self.tasks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::{core::node::Node, flow};
+
+    #[derive(Debug, PartialEq, Status)]
+    enum MyStatus {
+        #[status(default)]
+        Done,
+        #[status(failed)]
+        Failed,
+    }
+    struct SleepNode {}
+    #[async_trait::async_trait]
+    impl Node for SleepNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, _context: &mut Context) -> anyhow::Result<Value> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(Value::from("slept"))
+        }
+    }
+
+    struct PanicNode {}
+    #[async_trait::async_trait]
+    impl Node for PanicNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, _context: &mut Context) -> anyhow::Result<Value> {
+            panic!("node bug");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_task_manager() {
+        let manager = TaskManager::new(1);
+        let sleep = Arc::new(flow!(start: ("sleep", Arc::new(SleepNode {}))));
+        let first = manager.spawn("sleep", sleep.clone(), Context::new());
+        let second = manager.spawn("sleep", sleep.clone(), Context::new());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(manager.status(&first), Some(TaskStatus::Running));
+        assert_eq!(manager.status(&second), Some(TaskStatus::Queued));
+
+        assert!(manager.stop(&second));
+        let second = manager.wait(&second).await.unwrap();
+        assert_eq!(second.status, TaskStatus::Cancelled);
+        assert!(second.started_at.is_none());
+
+        let first = manager.wait(&first).await.unwrap();
+        assert_eq!(first.status, TaskStatus::Finished);
+        assert_eq!(first.result, Some(Value::from("slept")));
+        assert!(!manager.stop(&first.task_id));
+        assert!(manager.listen(&first.task_id).is_some());
+        assert_eq!(manager.list().len(), 2);
+        assert!(manager.remove(&first.task_id).is_some());
+        assert_eq!(manager.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_task_manager_breakpoint() {
+        let manager = TaskManager::new(2);
+        let mut f = flow!(start: ("sleep", Arc::new(SleepNode {})));
+        f.add_breakpoint("sleep");
+        let task = manager.spawn("paused", Arc::new(f), Context::new());
+
+        let mut info = manager.subscribe(&task).unwrap();
+        let paused = info
+            .wait_for(|info| info.status == TaskStatus::Paused)
+            .await
+            .unwrap()
+            .clone();
+        assert_eq!(paused.paused_at.as_deref(), Some("sleep"));
+
+        let edits = HashMap::from([("result".to_owned(), Value::from("edited"))]);
+        assert!(manager.resume(&task, edits).await);
+        let done = manager.wait(&task).await.unwrap();
+        assert_eq!(done.status, TaskStatus::Finished);
+        assert_eq!(done.result, Some(Value::from("edited")));
+    }
+
+    #[tokio::test]
+    async fn test_task_manager_failures() {
+        // no concurrency is taken as 1, the task still runs
+        let manager = TaskManager::new(0).with_retention(Duration::from_millis(20));
+        let sleep = Arc::new(flow!(start: ("sleep", Arc::new(SleepNode {}))));
+        let task = manager.spawn("sleep", sleep, Context::new());
+        let done = manager.wait(&task).await.unwrap();
+        assert_eq!(done.status, TaskStatus::Finished);
+
+        let panics = Arc::new(flow!(start: ("panic", Arc::new(PanicNode {}))));
+        let task = manager.spawn("panic", panics, Context::new());
+        let done = manager.wait(&task).await.unwrap();
+        assert_eq!(done.status, TaskStatus::Failed);
+        assert!(done.error.unwrap().contains("panicked"));
+
+        // both are removed once their retention ended
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(manager.list().is_empty());
+    }
+}
@@ -0,0 +1,516 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::utils::MongoClient;
+
+use super::{
+    context::Context,
+    edge::{Edge, EdgeCondition, EdgePredicate},
+    error::FlowError,
+    fan_out::{FanOut, MergeStrategy},
+    flow::{Flow, FlowBudget},
+    node::{Node, NodeResult},
+    retry::RetryPolicy,
+    status::Status,
+};
+
+pub static TEMPLATE_COLLECTION: &str = "flow_templates";
+
+/// A serializable flow definition, the nodes are created by a [`NodeRegistry`].
+///
+/// Observers, the node cache and `RetryPolicy::retry_if` are code, not data,
+/// add them to the built flow.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowTemplate {
+    pub name: String,
+    /// name of the start node
+    pub start: String,
+    pub nodes: Vec<NodeTemplate>,
+    #[serde(default)]
+    pub edges: Vec<EdgeTemplate>,
+    #[serde(default)]
+    pub fan_outs: Vec<FanOutTemplate>,
+    #[serde(default)]
+    pub breakpoints: Vec<String>,
+    #[serde(default)]
+    pub budget: Option<BudgetTemplate>,
+    /// see `Flow::with_fallback`
+    #[serde(default)]
+    pub fallback: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeTemplate {
+    pub name: String,
+    /// the type name the node factory is registered with
+    pub r#type: String,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default)]
+    pub retry: Option<RetryTemplate>,
+    /// see `Flow::set_timeout`
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+/// An edge taken on `status`, on the predicate `when` or `on` an error or by default,
+/// exactly one of them is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EdgeTemplate {
+    pub from: String,
+    /// parsed into the flow status by `FromStr`
+    #[serde(default)]
+    pub status: Option<String>,
+    /// the name the predicate is registered with, see [`NodeRegistry::register_predicate`]
+    #[serde(default)]
+    pub when: Option<String>,
+    #[serde(default)]
+    pub on: Option<EdgeOn>,
+    pub to: String,
+    #[serde(default)]
+    pub priority: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeOn {
+    /// see `Flow::add_error_edge`
+    Error,
+    /// see `Flow::add_default_edge`
+    Default,
+}
+
+/// see [`RetryPolicy`], the unset fields keep its defaults
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryTemplate {
+    pub max_attempts: usize,
+    #[serde(default)]
+    pub initial_backoff_ms: Option<u64>,
+    #[serde(default)]
+    pub max_backoff_ms: Option<u64>,
+    #[serde(default)]
+    pub multiplier: Option<f64>,
+    #[serde(default)]
+    pub jitter: Option<f64>,
+}
+
+/// see [`FlowBudget`]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BudgetTemplate {
+    #[serde(default)]
+    pub max_steps: Option<usize>,
+    #[serde(default)]
+    pub max_visits: Option<usize>,
+    #[serde(default)]
+    pub deadline_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FanOutTemplate {
+    pub name: String,
+    pub branches: Vec<String>,
+    pub join: String,
+    #[serde(default)]
+    pub at_least: Option<usize>,
+    #[serde(default)]
+    pub merge: MergeStrategy,
+}
+
+impl FlowTemplate {
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn from_json(s: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+}
+
+impl RetryTemplate {
+    fn policy(&self, node: &str) -> Result<RetryPolicy, FlowError> {
+        let defaults = RetryPolicy::default();
+        let initial = self.initial_backoff_ms.map(Duration::from_millis);
+        let max = self.max_backoff_ms.map(Duration::from_millis);
+        let mut policy = RetryPolicy::new(self.max_attempts).with_backoff(
+            initial.unwrap_or(defaults.initial_backoff),
+            max.unwrap_or(defaults.max_backoff),
+        );
+        let invalid = |e: FlowError| match e {
+            FlowError::Retry(e) => FlowError::Template(format!("node '{node}' retry {e}")),
+            e => e,
+        };
+        if let Some(multiplier) = self.multiplier {
+            policy = policy.with_multiplier(multiplier).map_err(invalid)?;
+        }
+        if let Some(jitter) = self.jitter {
+            policy = policy.with_jitter(jitter).map_err(invalid)?;
+        }
+        Ok(policy)
+    }
+}
+
+impl From<&BudgetTemplate> for FlowBudget {
+    fn from(budget: &BudgetTemplate) -> Self {
+        FlowBudget {
+            max_steps: budget.max_steps,
+            max_visits: budget.max_visits,
+            deadline: budget.deadline_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+pub type NodeFactory<S> =
+    Box<dyn Fn(Value) -> anyhow::Result<Arc<dyn Node<FlowStatus = S>>> + Send + Sync>;
+
+/// Node factories by type name and edge predicates by name,
+/// to build a `Flow` from a [`FlowTemplate`].
+pub struct NodeRegistry<S: Status> {
+    factories: HashMap<String, NodeFactory<S>>,
+    predicates: HashMap<String, EdgePredicate<S>>,
+}
+
+impl<S: Status> Default for NodeRegistry<S> {
+    fn default() -> Self {
+        NodeRegistry {
+            factories: HashMap::new(),
+            predicates: HashMap::new(),
+        }
+    }
+}
+
+impl<S: Status> NodeRegistry<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `f` creates the node from the template `params` deserialized as `T`
+    pub fn register<T, F>(&mut self, type_name: &str, f: F)
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(T) -> Arc<dyn Node<FlowStatus = S>> + Send + Sync + 'static,
+    {
+        let type_name_owned = type_name.to_owned();
+        let factory: NodeFactory<S> = Box::new(move |params: Value| {
+            let params: T = serde_json::from_value(params).map_err(|e| {
+                FlowError::Template(format!("invalid params of '{type_name_owned}': {e}"))
+            })?;
+            Ok(f(params))
+        });
+        self.factories.insert(type_name.to_owned(), factory);
+    }
+
+    /// the predicate of the template edges with `when = "<name>"`
+    pub fn register_predicate(
+        &mut self,
+        name: &str,
+        predicate: impl Fn(&NodeResult<S>, &Context) -> bool + Send + Sync + 'static,
+    ) {
+        self.predicates.insert(name.to_owned(), Arc::new(predicate));
+    }
+
+    pub fn create(&self, node: &NodeTemplate) -> anyhow::Result<Arc<dyn Node<FlowStatus = S>>> {
+        let factory = self.factories.get(&node.r#type).ok_or_else(|| {
+            FlowError::Template(format!(
+                "node '{}' has unknown type '{}'",
+                node.name, node.r#type
+            ))
+        })?;
+        factory(node.params.clone())
+    }
+
+    pub fn build(&self, template: &FlowTemplate) -> anyhow::Result<Flow<S>>
+    where
+        S: FromStr,
+    {
+        let start = template
+            .nodes
+            .iter()
+            .find(|node| node.name == template.start)
+            .ok_or_else(|| {
+                FlowError::Template(format!("start node '{}' is not defined", template.start))
+            })?;
+        let mut flow = Flow::new(&start.name, self.create(start)?);
+        for node in template.nodes.iter().filter(|node| node.name != start.name) {
+            flow.add_node(&node.name, self.create(node)?);
+        }
+        for node in &template.nodes {
+            if let Some(retry) = &node.retry {
+                flow.set_retry(&node.name, retry.policy(&node.name)?);
+            }
+            if let Some(timeout_ms) = node.timeout_ms {
+                flow.set_timeout(&node.name, Duration::from_millis(timeout_ms));
+            }
+        }
+        for edge in &template.edges {
+            flow.insert_edge(&edge.from, self.edge(edge)?.with_priority(edge.priority));
+        }
+        for fan_out in &template.fan_outs {
+            let branches = fan_out
+                .branches
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            let mut built = FanOut::new(&branches, &fan_out.join).with_merge(fan_out.merge.clone());
+            if let Some(n) = fan_out.at_least {
+                built = built.at_least(n);
+            }
+            flow.add_fan_out(&fan_out.name, built);
+        }
+        for node in &template.breakpoints {
+            flow.add_breakpoint(node);
+        }
+        if let Some(budget) = &template.budget {
+            flow.set_budget(budget.into());
+        }
+        if let Some(fallback) = &template.fallback {
+            flow.set_fallback(fallback);
+        }
+        Ok(flow.checked()?)
+    }
+
+    fn edge(&self, edge: &EdgeTemplate) -> Result<Edge<S>, FlowError>
+    where
+        S: FromStr,
+    {
+        let invalid = |problem: String| {
+            FlowError::Template(format!("edge '{}' -> '{}' {problem}", edge.from, edge.to))
+        };
+        match (&edge.status, &edge.when, edge.on) {
+            (Some(status), None, None) => {
+                let status = S::from_str(status)
+                    .map_err(|_| invalid(format!("has unknown status '{status}'")))?;
+                Ok(Edge::status(status, &edge.to))
+            }
+            (None, Some(when), None) => {
+                let predicate = self
+                    .predicates
+                    .get(when)
+                    .cloned()
+                    .ok_or_else(|| invalid(format!("has unknown predicate '{when}'")))?;
+                Ok(Edge {
+                    condition: EdgeCondition::When {
+                        label: when.clone(),
+                        predicate,
+                    },
+                    to: edge.to.clone(),
+                    priority: 0,
+                })
+            }
+            (None, None, Some(EdgeOn::Error)) => Ok(Edge::on_error(&edge.to)),
+            (None, None, Some(EdgeOn::Default)) => Ok(Edge::otherwise(&edge.to)),
+            _ => Err(invalid(
+                "needs exactly one of `status`, `when` and `on`".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Keeps the flow templates in mongo, by template name.
+#[derive(Debug, Clone)]
+pub struct TemplateStore {
+    collection: mongodb::Collection<FlowTemplate>,
+}
+
+impl TemplateStore {
+    pub fn new(client: &MongoClient) -> Self {
+        TemplateStore {
+            collection: client.collection(TEMPLATE_COLLECTION),
+        }
+    }
+
+    pub async fn save(&self, template: &FlowTemplate) -> anyhow::Result<()> {
+        self.collection
+            .replace_one(doc! { "name": &template.name }, template)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn load(&self, name: &str) -> anyhow::Result<Option<FlowTemplate>> {
+        Ok(self.collection.find_one(doc! { "name": name }).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::core::{
+        context::CONTEXT_RESULT,
+        error::{BudgetExceeded, FlowError},
+        status::Status,
+    };
+
+    #[derive(Debug, PartialEq, Status)]
+    enum MyStatus {
+        #[status(default)]
+        Done,
+        Long,
+        #[status(failed)]
+        Failed,
+    }
+
+    #[derive(Deserialize)]
+    struct AppendParams {
+        text: String,
+        #[serde(default)]
+        fail: bool,
+    }
+    struct AppendNode {
+        text: String,
+        fail: bool,
+    }
+    #[async_trait::async_trait]
+    impl Node for AppendNode {
+        type FlowStatus = MyStatus;
+
+        async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+            if self.fail {
+                anyhow::bail!("{} failed", self.text);
+            }
+            let current = context.get(CONTEXT_RESULT).and_then(Value::as_str);
+            Ok(Value::from(format!(
+                "{}{}",
+                current.unwrap_or(""),
+                self.text
+            )))
+        }
+    }
+
+    static TEMPLATE: &str = r#"
+name = "writer"
+start = "write"
+
+[[nodes]]
+name = "write"
+type = "append"
+params = { text = "draft" }
+
+[[nodes]]
+name = "edit"
+type = "append"
+params = { text = ", edited" }
+
+[[edges]]
+from = "write"
+status = "Done"
+to = "edit"
+"#;
+
+    fn registry() -> NodeRegistry<MyStatus> {
+        let mut registry = NodeRegistry::new();
+        registry.register("append", |params: AppendParams| {
+            Arc::new(AppendNode {
+                text: params.text,
+                fail: params.fail,
+            }) as Arc<dyn Node<FlowStatus = MyStatus>>
+        });
+        registry.register_predicate("too long", |_, context| {
+            context
+                .get(CONTEXT_RESULT)
+                .and_then(Value::as_str)
+                .is_some_and(|result| result.len() > 10)
+        });
+        registry
+    }
+
+    #[tokio::test]
+    async fn test_flow_template() {
+        let registry = registry();
+        let template = FlowTemplate::from_toml(TEMPLATE).unwrap();
+        let flow = registry.build(&template).unwrap();
+        let result = flow.run(Context::new()).await.unwrap();
+        assert_eq!(result, Value::from("draft, edited"));
+
+        let mut template = template;
+        template.edges[0].status = Some("Unknown".to_owned());
+        assert!(registry.build(&template).is_err());
+        template.edges[0].on = Some(EdgeOn::Default);
+        assert!(registry.build(&template).is_err());
+        template.edges[0].status = None;
+        template.nodes[1].r#type = "missing".to_owned();
+        assert!(registry.build(&template).is_err());
+    }
+
+    static FULL_TEMPLATE: &str = r#"
+name = "publisher"
+start = "write"
+fallback = "apologize"
+budget = { max_steps = 10, deadline_ms = 1000 }
+
+[[nodes]]
+name = "write"
+type = "append"
+params = { text = "a long draft" }
+timeout_ms = 100
+
+[[nodes]]
+name = "shorten"
+type = "append"
+params = { text = "publish", fail = true }
+retry = { max_attempts = 2, initial_backoff_ms = 1 }
+
+[[nodes]]
+name = "publish"
+type = "append"
+params = { text = " published" }
+
+[[nodes]]
+name = "apologize"
+type = "append"
+params = { text = ", sorry" }
+
+[[edges]]
+from = "write"
+when = "too long"
+to = "shorten"
+priority = 1
+
+[[edges]]
+from = "write"
+on = "default"
+to = "publish"
+"#;
+
+    #[tokio::test]
+    async fn test_flow_template_full() {
+        let registry = registry();
+        let mut template = FlowTemplate::from_toml(FULL_TEMPLATE).unwrap();
+        // too long, `shorten` fails twice, the fallback takes over
+        let flow = registry.build(&template).unwrap();
+        let (result, report) = flow.run_with_report(Context::new()).await;
+        assert_eq!(result.unwrap(), Value::from("a long draft, sorry"));
+        let shorten = report
+            .nodes
+            .iter()
+            .find(|run| run.node == "shorten")
+            .unwrap();
+        assert_eq!(shorten.retried.len(), 1);
+
+        template.nodes[0].params = serde_json::json!({ "text": "short" });
+        let flow = registry.build(&template).unwrap();
+        let result = flow.run(Context::new()).await.unwrap();
+        assert_eq!(result, Value::from("short published"));
+
+        template.budget = Some(BudgetTemplate {
+            max_steps: Some(1),
+            ..Default::default()
+        });
+        let flow = registry.build(&template).unwrap();
+        let err = flow.run(Context::new()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlowError>(),
+            Some(FlowError::BudgetExceeded(BudgetExceeded::MaxSteps(1)))
+        ));
+
+        template.edges[0].when = Some("unknown".to_owned());
+        assert!(registry.build(&template).is_err());
+        template.edges[0].when = Some("too long".to_owned());
+        template.nodes[1].retry.as_mut().unwrap().multiplier = Some(-2.0);
+        let err = registry.build(&template).err().unwrap();
+        assert!(err.to_string().contains("retry multiplier must be finite"));
+    }
+}
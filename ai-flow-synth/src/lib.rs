@@ -1,3 +1,6 @@
+// lets `#[derive(Status)]` refer to `::ai_flow_synth` inside this crate too
+extern crate self as ai_flow_synth;
+
 pub mod core;
 pub mod llm;
 pub mod utils;
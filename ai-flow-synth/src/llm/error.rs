@@ -15,11 +15,11 @@ pub enum LLMError {
     #[error("LLMError Tool: {0}")]
     Tool(String),
 
-    #[error("LLMError SteamSendError: {0}")]
-    StreamSendError(
-        #[from]
-        tokio::sync::broadcast::error::SendError<crate::core::stream_message::StreamMessage>,
-    ),
+    #[error("LLMError Cassette: {0}")]
+    Cassette(String),
+
+    #[error("LLMError Cancelled")]
+    Cancelled,
 }
 
 pub type LLMResult<T> = Result<T, LLMError>;
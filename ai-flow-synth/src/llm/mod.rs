@@ -7,44 +7,66 @@ pub mod tool;
 use error::LLMResult;
 use model::{ChatMessage, ChatMessageDelta, ToolCall};
 use provider::{LLMCallProcess, LLMProvider};
-use tokio::sync::broadcast::Sender;
 use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 use tool::ToolRegistry;
 use tracing::{error, info};
 
-use crate::core::stream_message::StreamMessage;
+use crate::core::stream_message::{StreamMessage, StreamSender};
 
+/// Chat until the model answers without tool calls, fails with `LLMError::Cancelled`
+/// once `cancel` is cancelled.
 pub async fn chat(
-    mut messages: Vec<ChatMessage>,
-    stream: Sender<StreamMessage>,
+    messages: Vec<ChatMessage>,
+    stream: StreamSender,
     client: &impl LLMProvider,
     registry: &ToolRegistry,
+    cancel: &CancellationToken,
 ) -> LLMResult<String> {
+    let (content, _) = chat_with_usage(messages, stream, client, registry, cancel).await?;
+    Ok(content)
+}
+
+/// Same as [`chat`], also returns the total tokens the provider reported over all rounds.
+pub async fn chat_with_usage(
+    mut messages: Vec<ChatMessage>,
+    stream: StreamSender,
+    client: &impl LLMProvider,
+    registry: &ToolRegistry,
+    cancel: &CancellationToken,
+) -> LLMResult<(String, i64)> {
     let mut content = String::new();
+    let mut total_tokens = 0;
     let mut tool_call = ToolCall::default();
     let mut current_process = LLMCallProcess::ChatStream;
     while current_process != LLMCallProcess::Finish {
         match current_process {
             LLMCallProcess::ChatStream => {
                 current_process = LLMCallProcess::Finish; // default to finish
-                let mut chat_stream = client.chat_stream(&messages).await?;
+                let mut chat_stream = client.chat_stream(&messages, cancel).await?;
                 while let Some(chunk) = chat_stream.next().await {
                     let chunk = chunk?;
+                    if let Some(tokens) = chunk.total_tokens {
+                        total_tokens += tokens;
+                        stream.send(StreamMessage::Usage {
+                            total_tokens: tokens,
+                        });
+                    }
                     match chunk.delta {
                         ChatMessageDelta::Content(s) => {
                             content.push_str(&s);
                             if s.is_empty() {
                                 continue; // skip empty deltas
                             }
-                            stream.send(StreamMessage::Delta(s))?;
+                            stream.send(StreamMessage::Delta(s));
                         }
-                        ChatMessageDelta::ToolCalls(chunk) => {
-                            if chunk.id.is_some() {
-                                if let Some(name) = &chunk.function.name {
-                                    stream
-                                        .send(StreamMessage::Procedure(format!("Tools: {name}")))?;
-                                }
+                        ChatMessageDelta::Reasoning(s) => {
+                            if s.is_empty() {
+                                continue;
                             }
+                            stream.send(StreamMessage::Thinking(s));
+                        }
+                        ChatMessageDelta::ToolCalls(chunk) => {
                             current_process = LLMCallProcess::FunctionCall;
                             tool_call = tool_call.extend_chunk(chunk);
                         }
@@ -53,10 +75,22 @@ pub async fn chat(
             }
             LLMCallProcess::FunctionCall => {
                 current_process = LLMCallProcess::Finish; // default to finish
+                let arguments = serde_json::from_str(&tool_call.function.arguments)
+                    .unwrap_or_else(|_| tool_call.function.arguments.clone().into());
+                stream.send(StreamMessage::ToolCall {
+                    id: tool_call.id.clone(),
+                    name: tool_call.function.name.clone(),
+                    arguments,
+                });
                 if let Some((f, _, _)) = registry.get(&tool_call.function.name) {
                     let r = f(serde_json::from_str(&tool_call.function.arguments)?);
                     messages.push(ChatMessage::assistant("").with_tool_call(tool_call.clone()));
                     info!("Tool call result: {:?}", r);
+                    stream.send(StreamMessage::ToolResult {
+                        id: tool_call.id.clone(),
+                        name: tool_call.function.name.clone(),
+                        result: r.clone(),
+                    });
                     messages.push(ChatMessage::tool(
                         serde_json::to_string(&r)?,
                         tool_call.id.clone(),
@@ -68,6 +102,9 @@ pub async fn chat(
                         "Tool function '{}' not found in registry",
                         &tool_call.function.name
                     );
+                    stream.send(StreamMessage::Error {
+                        message: format!("tool '{}' not found", tool_call.function.name),
+                    });
                 }
             }
             LLMCallProcess::Finish => {
@@ -75,5 +112,5 @@ pub async fn chat(
             }
         }
     }
-    Ok(content)
+    Ok((content, total_tokens))
 }
@@ -15,7 +15,7 @@ pub struct ChatMessage {
     pub role: ChatMessageRole,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_call_id: Option<String>, // tool call id, if role is Tool, this is a tool call result message
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub tool_calls: Vec<ToolCall>, // tool calls in the message
 }
 
@@ -166,6 +166,7 @@ pub enum FinishReason {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ChatMessageDelta {
-    Content(String), // The content of the message
+    Content(String),   // The content of the message
+    Reasoning(String), // The thinking before the content, e.g. of deepseek-reasoner
     ToolCalls(ChunkToolCall),
 }
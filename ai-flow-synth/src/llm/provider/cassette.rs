@@ -0,0 +1,494 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    llm::error::{LLMError, LLMResult},
+    utils::stable_hash,
+};
+
+use super::EventData;
+
+/// env var to switch the mode of [`CassettePlayer::from_env`]
+pub static CASSETTE_MODE_ENV: &str = "LLM_CASSETTE";
+
+/// the last event of a complete response of an OpenAI compatible api
+static DONE_EVENT: &str = "[DONE]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CassetteMode {
+    /// call the api and record its events to the cassette
+    Record,
+    /// answer from the cassette only, the api is never called
+    #[default]
+    Replay,
+    /// call the api, the cassette is not touched
+    Passthrough,
+}
+
+impl FromStr for CassetteMode {
+    type Err = LLMError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "record" => Ok(CassetteMode::Record),
+            "replay" => Ok(CassetteMode::Replay),
+            "passthrough" => Ok(CassetteMode::Passthrough),
+            _ => Err(LLMError::Cassette(format!("unknown mode '{s}'"))),
+        }
+    }
+}
+
+/// The server-sent events of the responses of an api, recorded as they were received.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+/// One request and the events of its response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    /// [`request_hash`] of the provider and the body
+    pub request: String,
+    pub provider: String,
+    /// the request body, with the model, the messages and the tools
+    pub body: Value,
+    /// the `data` of each event in order, e.g. the chunk json and `[DONE]`
+    pub events: Vec<String>,
+    /// the error the response ended with, replayed after the events
+    #[serde(default)]
+    pub error: Option<String>,
+    /// the response was cut off before its end, replaying it fails after the events
+    #[serde(default)]
+    pub partial: bool,
+}
+
+impl Cassette {
+    /// an empty cassette if the file does not exist yet
+    pub fn load(path: &Path) -> LLMResult<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => Ok(serde_json::from_str(&json)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Cassette::default()),
+            Err(e) => Err(LLMError::Cassette(format!("read {}: {e}", path.display()))),
+        }
+    }
+
+    pub async fn save(&self, path: &Path) -> LLMResult<()> {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| LLMError::Cassette(format!("create {}: {e}", dir.display())))?;
+        }
+        tokio::fs::write(path, serde_json::to_string_pretty(self)?)
+            .await
+            .map_err(|e| LLMError::Cassette(format!("write {}: {e}", path.display())))
+    }
+}
+
+/// A hash of the provider and the request body (the model, the messages and the tools)
+/// that is stable across runs and platforms.
+pub fn request_hash(provider: &str, body: &Value) -> LLMResult<String> {
+    let request = serde_json::to_vec(&(provider, body))?;
+    Ok(stable_hash(&request))
+}
+
+/// Records or replays the server-sent events of the requests of the clients it is
+/// given to, see `DeepSeekClient::with_cassette`, to run the same tests against the real
+/// api once and offline in CI. The responses are parsed by the client in both modes.
+///
+/// A recorded request replaces the responses the cassette had for it, the responses
+/// to other requests are kept. Recorded responses are written by [`CassettePlayer::save`].
+pub struct CassettePlayer {
+    mode: CassetteMode,
+    path: PathBuf,
+    state: Mutex<PlayerState>,
+}
+
+#[derive(Default)]
+struct PlayerState {
+    cassette: Cassette,
+    /// the requests recorded so far, their loaded responses are replaced
+    recorded: HashSet<String>,
+    // <request, times replayed>
+    replayed: HashMap<String, usize>,
+}
+
+impl CassettePlayer {
+    pub fn new(mode: CassetteMode, path: impl Into<PathBuf>) -> LLMResult<Arc<Self>> {
+        let path = path.into();
+        let cassette = match mode {
+            CassetteMode::Passthrough => Cassette::default(),
+            _ => Cassette::load(&path)?,
+        };
+        Ok(Arc::new(CassettePlayer {
+            mode,
+            path,
+            state: Mutex::new(PlayerState {
+                cassette,
+                ..Default::default()
+            }),
+        }))
+    }
+
+    /// the mode from [`CASSETTE_MODE_ENV`], replay if it is not set
+    pub fn from_env(path: impl Into<PathBuf>) -> LLMResult<Arc<Self>> {
+        let mode = match std::env::var(CASSETTE_MODE_ENV) {
+            Ok(mode) => mode.parse()?,
+            Err(_) => CassetteMode::default(),
+        };
+        CassettePlayer::new(mode, path)
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    /// Write the recorded responses to the cassette file, only when recording.
+    pub async fn save(&self) -> LLMResult<()> {
+        if self.mode != CassetteMode::Record {
+            return Ok(());
+        }
+        let cassette = self.state.lock().unwrap().cassette.clone();
+        cassette.save(&self.path).await
+    }
+
+    /// The events of the request `body` to `provider`, `call` sends it to the api.
+    pub(crate) fn events(
+        self: &Arc<Self>,
+        provider: &str,
+        body: &Value,
+        call: impl FnOnce() -> LLMResult<EventData>,
+    ) -> LLMResult<EventData> {
+        let request = request_hash(provider, body)?;
+        match self.mode {
+            CassetteMode::Passthrough => call(),
+            CassetteMode::Replay => self.replay(&request),
+            CassetteMode::Record => {
+                let mut events = call()?;
+                let mut recording = Recording {
+                    player: self.clone(),
+                    interaction: Some(Interaction {
+                        request,
+                        provider: provider.to_owned(),
+                        body: body.clone(),
+                        events: Vec::new(),
+                        error: None,
+                        partial: false,
+                    }),
+                    complete: false,
+                };
+                Ok(Box::pin(async_stream::stream!({
+                    while let Some(item) = events.next().await {
+                        recording.push(&item);
+                        yield item;
+                    }
+                    recording.finish();
+                })))
+            }
+        }
+    }
+
+    /// the same request recorded more than once is replayed in the recorded order
+    fn replay(&self, request: &str) -> LLMResult<EventData> {
+        let mut state = self.state.lock().unwrap();
+        let PlayerState {
+            cassette, replayed, ..
+        } = &mut *state;
+        let recorded = cassette
+            .interactions
+            .iter()
+            .filter(|interaction| interaction.request == request)
+            .collect::<Vec<_>>();
+        let times = replayed.entry(request.to_owned()).or_default();
+        // the last response answers all further calls
+        let interaction = recorded
+            .get(*times)
+            .or(recorded.last())
+            .map(|interaction| (*interaction).clone())
+            .ok_or_else(|| {
+                LLMError::Cassette(format!("no recorded response for request {request}"))
+            })?;
+        *times += 1;
+        Ok(Box::pin(async_stream::stream!({
+            for data in interaction.events {
+                yield Ok(data);
+            }
+            if let Some(error) = interaction.error {
+                yield Err(LLMError::LLMProvider(error));
+            } else if interaction.partial {
+                yield Err(LLMError::Cassette(format!(
+                    "the recorded response to request {} was cut off",
+                    interaction.request
+                )));
+            }
+        })))
+    }
+
+    fn add(&self, interaction: Interaction) {
+        let mut state = self.state.lock().unwrap();
+        if state.recorded.insert(interaction.request.clone()) {
+            state
+                .cassette
+                .interactions
+                .retain(|recorded| recorded.request != interaction.request);
+        }
+        state.cassette.interactions.push(interaction);
+    }
+}
+
+/// A response being recorded, added to the cassette when its stream ends or is dropped.
+/// A response dropped before its end is marked partial, one dropped unread is skipped.
+struct Recording {
+    player: Arc<CassettePlayer>,
+    interaction: Option<Interaction>,
+    complete: bool,
+}
+
+impl Recording {
+    fn push(&mut self, item: &LLMResult<String>) {
+        let Some(interaction) = &mut self.interaction else {
+            return;
+        };
+        match item {
+            Ok(data) => {
+                self.complete = data.trim() == DONE_EVENT;
+                interaction.events.push(data.clone());
+            }
+            Err(LLMError::LLMProvider(message)) => {
+                interaction.error = Some(message.clone());
+                self.complete = true;
+            }
+            Err(e) => {
+                interaction.error = Some(e.to_string());
+                self.complete = true;
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        self.complete = true;
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let Some(mut interaction) = self.interaction.take() else {
+            return;
+        };
+        if !self.complete {
+            if interaction.events.is_empty() {
+                return; // never read
+            }
+            tracing::warn!(
+                "Response to request {} cut off after {} events, recorded as partial",
+                interaction.request,
+                interaction.events.len()
+            );
+            interaction.partial = true;
+        }
+        self.player.add(interaction);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::llm::{
+        model::{ChatMessage, ChatMessageDelta},
+        provider::{LLMProvider, deepseek::DeepSeekClient, openai::OpenAIClient},
+    };
+
+    fn chunk(content: &str) -> String {
+        serde_json::json!({
+            "id": "1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "deepseek-chat",
+            "choices": [{ "index": 0, "delta": { "content": content }, "finish_reason": null }],
+        })
+        .to_string()
+    }
+
+    /// serves one response of `responses` per request as server-sent events,
+    /// the connection is closed after the events
+    async fn serve(responses: Vec<Vec<String>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for events in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // the headers and the json body
+                while !request.ends_with(b"}") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let mut response = "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\
+                                    connection: close\r\n\r\n"
+                    .to_owned();
+                for data in events {
+                    response.push_str(&format!("data: {data}\n\n"));
+                }
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        url
+    }
+
+    async fn collect(provider: &impl LLMProvider, question: &str) -> Vec<Result<String, String>> {
+        let stream = provider
+            .chat_stream(&[ChatMessage::user(question)], &CancellationToken::new())
+            .await
+            .unwrap();
+        stream
+            .map(|item| match item {
+                Ok(chunk) => match chunk.delta {
+                    ChatMessageDelta::Content(content) => Ok(content),
+                    delta => Ok(format!("{delta:?}")),
+                },
+                Err(e) => Err(e.to_string()),
+            })
+            .collect()
+            .await
+    }
+
+    fn deepseek(url: &str, model: &str, cassette: &Arc<CassettePlayer>) -> DeepSeekClient {
+        DeepSeekClient::new(String::new(), url.to_owned(), model.to_owned())
+            .with_cassette(cassette.clone())
+    }
+
+    /// nothing listens on the port, replaying must not call the api
+    static NO_API: &str = "http://127.0.0.1:9";
+
+    #[tokio::test]
+    async fn test_cassette_record_replay() {
+        let path = std::env::temp_dir().join(format!("cassette-{}.json", uuid::Uuid::new_v4()));
+        let url = serve(vec![
+            vec![chunk("Hel"), chunk("lo"), DONE_EVENT.to_owned()],
+            vec![chunk("first"), DONE_EVENT.to_owned()],
+            vec![chunk("second"), DONE_EVENT.to_owned()],
+            // closed without `[DONE]`
+            vec![chunk("over")],
+        ])
+        .await;
+        let recorder = CassettePlayer::new(CassetteMode::Record, &path).unwrap();
+        let client = deepseek(&url, "deepseek-chat", &recorder);
+        let hello = collect(&client, "Hi").await;
+        assert_eq!(hello, vec![Ok("Hel".into()), Ok("lo".into())]);
+        collect(&client, "again").await;
+        collect(&client, "again").await;
+        let fail = collect(&client, "fail").await;
+        assert_eq!(fail[0], Ok("over".into()));
+        assert!(fail[1].as_ref().unwrap_err().contains("DeepSeek API error"));
+        assert!(!path.exists());
+        recorder.save().await.unwrap();
+
+        let cassette = Cassette::load(&path).unwrap();
+        assert_eq!(cassette.interactions.len(), 4);
+        assert_eq!(cassette.interactions[0].events[0], chunk("Hel"));
+        assert_eq!(cassette.interactions[0].body["model"], "deepseek-chat");
+
+        // the client parses the replayed events
+        let replayer = CassettePlayer::new(CassetteMode::Replay, &path).unwrap();
+        let client = deepseek(NO_API, "deepseek-chat", &replayer);
+        assert_eq!(collect(&client, "Hi").await, hello);
+        assert_eq!(collect(&client, "again").await[0], Ok("first".into()));
+        assert_eq!(collect(&client, "again").await[0], Ok("second".into()));
+        assert_eq!(collect(&client, "again").await[0], Ok("second".into()));
+        assert_eq!(collect(&client, "fail").await, fail);
+
+        // another model, tool set or provider is another request
+        let unknown = |e: LLMResult<_>| matches!(e, Err(LLMError::Cassette(_)));
+        let messages = [ChatMessage::user("Hi")];
+        let cancel = CancellationToken::new();
+        let other_model = deepseek(NO_API, "deepseek-reasoner", &replayer);
+        assert!(unknown(other_model.chat_stream(&messages, &cancel).await));
+        let mut with_tool = deepseek(NO_API, "deepseek-chat", &replayer);
+        with_tool.add_tool(serde_json::json!({ "type": "function" }));
+        assert!(unknown(with_tool.chat_stream(&messages, &cancel).await));
+        let openai = OpenAIClient::new(String::new(), NO_API.to_owned(), "deepseek-chat".into())
+            .with_cassette(replayer.clone());
+        assert!(unknown(openai.chat_stream(&messages, &cancel).await));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            "passthrough".parse::<CassetteMode>().unwrap(),
+            CassetteMode::Passthrough
+        );
+        let url = serve(vec![vec![chunk("Hel"), chunk("lo"), DONE_EVENT.to_owned()]]).await;
+        let passthrough = CassettePlayer::new(CassetteMode::Passthrough, &path).unwrap();
+        assert_eq!(
+            collect(&deepseek(&url, "deepseek-chat", &passthrough), "Hi").await,
+            hello
+        );
+        passthrough.save().await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_cassette_rerecord() {
+        let path = std::env::temp_dir().join(format!("cassette-{}.json", uuid::Uuid::new_v4()));
+        let url = serve(vec![
+            vec![chunk("hello"), DONE_EVENT.to_owned()],
+            vec![chunk("bye"), DONE_EVENT.to_owned()],
+        ])
+        .await;
+        let recorder = CassettePlayer::new(CassetteMode::Record, &path).unwrap();
+        let client = deepseek(&url, "deepseek-chat", &recorder);
+        collect(&client, "Hi").await;
+        collect(&client, "Bye").await;
+        recorder.save().await.unwrap();
+
+        // only the recorded requests are replaced, the others are kept
+        let url = serve(vec![
+            vec![chunk("hello again"), DONE_EVENT.to_owned()],
+            vec![chunk("hello once more"), DONE_EVENT.to_owned()],
+            vec![chunk("Once upon"), chunk(" a time"), DONE_EVENT.to_owned()],
+        ])
+        .await;
+        let recorder = CassettePlayer::new(CassetteMode::Record, &path).unwrap();
+        let client = deepseek(&url, "deepseek-chat", &recorder);
+        collect(&client, "Hi").await;
+        collect(&client, "Hi").await;
+        // the stream is dropped after the first chunk
+        let mut story = client
+            .chat_stream(&[ChatMessage::user("Story")], &CancellationToken::new())
+            .await
+            .unwrap();
+        story.next().await.unwrap().unwrap();
+        drop(story);
+        recorder.save().await.unwrap();
+
+        let replayer = CassettePlayer::new(CassetteMode::Replay, &path).unwrap();
+        let client = deepseek(NO_API, "deepseek-chat", &replayer);
+        assert_eq!(collect(&client, "Hi").await[0], Ok("hello again".into()));
+        assert_eq!(
+            collect(&client, "Hi").await[0],
+            Ok("hello once more".into())
+        );
+        assert_eq!(collect(&client, "Bye").await[0], Ok("bye".into()));
+        // the cut off response fails after its events
+        let story = collect(&client, "Story").await;
+        assert_eq!(story[0], Ok("Once upon".into()));
+        assert!(story[1].as_ref().unwrap_err().contains("was cut off"));
+        let cassette = Cassette::load(&path).unwrap();
+        assert_eq!(cassette.interactions.len(), 4);
+        assert!(cassette.interactions[3].partial);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
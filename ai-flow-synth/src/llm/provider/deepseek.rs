@@ -1,10 +1,10 @@
 #![allow(dead_code)]
 
-use std::pin::Pin;
+use std::{pin::Pin, sync::Arc};
 
 use futures::{Stream, StreamExt};
-use reqwest_eventsource::{EventSource, RequestBuilderExt};
 use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 
 use crate::llm::{
@@ -12,7 +12,7 @@ use crate::llm::{
     model::{ChatMessage, ChatMessageDelta, ChatMessageRole, ChunkToolCall, FinishReason},
 };
 
-use super::{ChatMessageChunk, LLMProvider};
+use super::{ChatMessageChunk, EventData, LLMProvider, cassette::CassettePlayer, event_data};
 
 pub struct DeepSeekClient {
     client: reqwest::Client,
@@ -20,6 +20,7 @@ pub struct DeepSeekClient {
     base_url: String,
     model: String,
     tools: Vec<serde_json::Value>,
+    cassette: Option<Arc<CassettePlayer>>,
     // maybe other fields...
 }
 
@@ -42,9 +43,16 @@ impl DeepSeekClient {
             base_url,
             model,
             tools: Vec::new(),
+            cassette: None,
         }
     }
 
+    /// record or replay the responses with `cassette`
+    pub fn with_cassette(mut self, cassette: Arc<CassettePlayer>) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
     pub fn add_tool(&mut self, tool: serde_json::Value) {
         self.tools.push(tool);
     }
@@ -53,21 +61,20 @@ impl DeepSeekClient {
         self.tools.extend(tools);
     }
 
-    fn client_chat_stream(&self, message: &[ChatMessage]) -> LLMResult<EventSource> {
-        let resp = self
+    fn client_chat_stream(&self, message: &[ChatMessage]) -> LLMResult<EventData> {
+        let request = self
             .client
             .post(format!("{}/v1/chat/completions", self.base_url))
-            .bearer_auth(&self.api_key)
-            .json(&serde_json::json!(
-                {
-                    "model": self.model,
-                    "messages": message,
-                    "stream": true,
-                    "tools": self.tools,
-                }
-            ))
-            .eventsource()?;
-        Ok(resp)
+            .bearer_auth(&self.api_key);
+        let body = serde_json::json!(
+            {
+                "model": self.model,
+                "messages": message,
+                "stream": true,
+                "tools": self.tools,
+            }
+        );
+        event_data(request, "deepseek", body, self.cassette.as_ref())
     }
 
     async fn debug_chat(&self, messages: &[ChatMessage]) -> anyhow::Result<String> {
@@ -96,7 +103,7 @@ impl DeepSeekClient {
 impl LLMProvider for DeepSeekClient {
     #[instrument(
         name = "DeepSeekClient::chat_stream",
-        skip(self, messages),
+        skip(self, messages, cancel),
         fields(
             model = %self.model,
             base_url = %self.base_url
@@ -105,34 +112,37 @@ impl LLMProvider for DeepSeekClient {
     async fn chat_stream(
         &self,
         messages: &[ChatMessage],
+        cancel: &CancellationToken,
     ) -> LLMResult<Pin<Box<dyn Stream<Item = LLMResult<ChatMessageChunk>> + Send>>> {
-        let mut event_source = self.client_chat_stream(messages)?;
+        let mut event_data = self.client_chat_stream(messages)?;
+        let cancel = cancel.clone();
         let stream = async_stream::stream!({
-            while let Some(event) = event_source.next().await {
-                let event = event
-                    .map_err(|err| LLMError::LLMProvider(format!("DeepSeek API error: {}", err)))?;
-
-                let chunk: ChatMessageChunk = match event {
-                    reqwest_eventsource::Event::Open => {
-                        continue; // Open event, we can ignore it
-                    }
-                    reqwest_eventsource::Event::Message(event) => {
-                        let data = event.data;
-                        tracing::info!("Received DeepSeek API chunk: {}", data);
-                        if data.trim() == "[DONE]" {
-                            tracing::info!("DeepSeek API stream DONE");
-                            break;
-                        } else if let Ok(chunk) = serde_json::from_str::<DeepSeekChunkResp>(&data) {
-                            chunk.into()
-                        } else {
-                            tracing::error!("DeepSeek API response is not valid JSON: {}", data);
-                            continue; // Skip this chunk
-                        }
+            while let Some(data) = tokio::select! {
+                data = event_data.next() => data,
+                _ = cancel.cancelled() => None,
+            } {
+                let data = data.map_err(|err| match err {
+                    LLMError::LLMProvider(err) => {
+                        LLMError::LLMProvider(format!("DeepSeek API error: {}", err))
                     }
+                    err => err,
+                })?;
+                tracing::info!("Received DeepSeek API chunk: {}", data);
+                let chunk: ChatMessageChunk = if data.trim() == "[DONE]" {
+                    tracing::info!("DeepSeek API stream DONE");
+                    break;
+                } else if let Ok(chunk) = serde_json::from_str::<DeepSeekChunkResp>(&data) {
+                    chunk.into()
+                } else {
+                    tracing::error!("DeepSeek API response is not valid JSON: {}", data);
+                    continue; // Skip this chunk
                 };
                 tracing::info!("Yielding chunk: {:?}", chunk);
                 yield Ok(chunk);
             }
+            if cancel.is_cancelled() {
+                yield Err(LLMError::Cancelled);
+            }
         });
 
         Ok(Box::pin(stream))
@@ -159,6 +169,8 @@ struct DeepSeekChoice {
 #[derive(Debug, Clone, Deserialize)]
 struct DeepSeekDelta {
     content: Option<String>, // tool_call场景可能是 None
+    #[serde(default)]
+    reasoning_content: Option<String>, // deepseek-reasoner 思考过程
     role: Option<ChatMessageRole>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tool_calls: Option<Vec<ChunkToolCall>>,
@@ -176,15 +188,17 @@ impl From<DeepSeekChunkResp> for ChatMessageChunk {
         let delta = match (
             &resp.choices[0].delta.content,
             &resp.choices[0].delta.tool_calls,
+            &resp.choices[0].delta.reasoning_content,
         ) {
-            (Some(content), _) => ChatMessageDelta::Content(content.to_owned()),
-            (_, Some(tool_calls)) => match tool_calls.iter().next() {
+            (Some(content), _, _) => ChatMessageDelta::Content(content.to_owned()),
+            (_, Some(tool_calls), _) => match tool_calls.iter().next() {
                 Some(tool_call) => ChatMessageDelta::ToolCalls(tool_call.clone()),
                 None => {
                     tracing::warn!("No tool calls found in the response");
                     ChatMessageDelta::Content(String::new())
                 }
             },
+            (_, _, Some(reasoning)) => ChatMessageDelta::Reasoning(reasoning.to_owned()),
             _ => ChatMessageDelta::Content(String::new()),
         };
         ChatMessageChunk {
@@ -0,0 +1,347 @@
+use std::{collections::VecDeque, pin::Pin, sync::Mutex, time::Duration};
+
+use futures::Stream;
+use tokio_util::sync::CancellationToken;
+
+use crate::llm::{
+    error::{LLMError, LLMResult},
+    model::{
+        ChatMessage, ChatMessageChunk, ChatMessageDelta, ChunkToolCall, ChunkToolFunction,
+        FinishReason,
+    },
+};
+
+use super::LLMProvider;
+
+/// One event of a scripted response of [`MockProvider`]
+#[derive(Debug, Clone)]
+pub enum MockEvent {
+    Chunk(ChatMessageChunk),
+    /// the stream yields `LLMError::LLMProvider` with the message
+    Error(String),
+}
+
+impl MockEvent {
+    pub fn content(content: &str) -> Self {
+        MockEvent::delta(ChatMessageDelta::Content(content.to_owned()))
+    }
+
+    pub fn reasoning(reasoning: &str) -> Self {
+        MockEvent::delta(ChatMessageDelta::Reasoning(reasoning.to_owned()))
+    }
+
+    /// The first chunk of a tool call, with the id and the name,
+    /// the arguments may follow in [`MockEvent::tool_arguments`].
+    pub fn tool_call(id: &str, name: &str, arguments: &str) -> Self {
+        MockEvent::tool_call_at(0, id, name, arguments)
+    }
+
+    /// [`MockEvent::tool_call`] of the `index`-th tool call of a response with several
+    pub fn tool_call_at(index: i64, id: &str, name: &str, arguments: &str) -> Self {
+        MockEvent::delta(ChatMessageDelta::ToolCalls(ChunkToolCall {
+            id: Some(id.to_owned()),
+            index,
+            r#type: Some("function".to_owned()),
+            function: ChunkToolFunction {
+                name: Some(name.to_owned()),
+                arguments: arguments.to_owned(),
+            },
+        }))
+    }
+
+    /// a following chunk of a tool call, only a part of the arguments
+    pub fn tool_arguments(arguments: &str) -> Self {
+        MockEvent::tool_arguments_at(0, arguments)
+    }
+
+    /// [`MockEvent::tool_arguments`] of the `index`-th tool call
+    pub fn tool_arguments_at(index: i64, arguments: &str) -> Self {
+        MockEvent::delta(ChatMessageDelta::ToolCalls(ChunkToolCall {
+            id: None,
+            index,
+            r#type: None,
+            function: ChunkToolFunction {
+                name: None,
+                arguments: arguments.to_owned(),
+            },
+        }))
+    }
+
+    /// the last, empty chunk of a response
+    pub fn finish(reason: FinishReason, total_tokens: Option<i64>) -> Self {
+        let mut event = MockEvent::content("");
+        if let MockEvent::Chunk(chunk) = &mut event {
+            chunk.finish_reason = Some(reason);
+            chunk.total_tokens = total_tokens;
+        }
+        event
+    }
+
+    pub fn error(message: &str) -> Self {
+        MockEvent::Error(message.to_owned())
+    }
+
+    fn delta(delta: ChatMessageDelta) -> Self {
+        MockEvent::Chunk(ChatMessageChunk {
+            id: "mock".to_owned(),
+            delta_content: match &delta {
+                ChatMessageDelta::Content(content) => content.clone(),
+                _ => String::new(),
+            },
+            delta,
+            created: 0,
+            model: "mock".to_owned(),
+            finish_reason: None,
+            total_tokens: None,
+        })
+    }
+}
+
+/// An [`LLMProvider`] replaying scripted responses, one per `chat_stream` call in order,
+/// and recording the messages of each call, to test nodes and the tool loop offline.
+#[derive(Default)]
+pub struct MockProvider {
+    // `Err` fails the request itself
+    responses: Mutex<VecDeque<Result<Vec<MockEvent>, String>>>,
+    received: Mutex<Vec<Vec<ChatMessage>>>,
+    delay: Option<Duration>,
+}
+
+impl MockProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `MockProvider::new().with_response(vec![MockEvent::content("Hi"), ...])`
+    pub fn with_response(self, events: Vec<MockEvent>) -> Self {
+        self.push_response(events);
+        self
+    }
+
+    /// a response of the whole `content` followed by `FinishReason::Stop`
+    pub fn with_answer(self, content: &str) -> Self {
+        self.with_response(vec![
+            MockEvent::content(content),
+            MockEvent::finish(FinishReason::Stop, None),
+        ])
+    }
+
+    /// the call fails with `LLMError::LLMProvider` before any chunk
+    pub fn with_failed_request(self, message: &str) -> Self {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back(Err(message.to_owned()));
+        self
+    }
+
+    /// wait `delay` before each event, e.g. to cancel in the middle of a response
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    pub fn push_response(&self, events: Vec<MockEvent>) {
+        self.responses.lock().unwrap().push_back(Ok(events));
+    }
+
+    /// the messages of every `chat_stream` call so far
+    pub fn received(&self) -> Vec<Vec<ChatMessage>> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// the number of scripted responses not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.responses.lock().unwrap().len()
+    }
+}
+
+#[async_trait::async_trait]
+impl LLMProvider for MockProvider {
+    async fn chat_stream(
+        &self,
+        messages: &[ChatMessage],
+        cancel: &CancellationToken,
+    ) -> LLMResult<Pin<Box<dyn Stream<Item = LLMResult<ChatMessageChunk>> + Send>>> {
+        self.received.lock().unwrap().push(messages.to_vec());
+        let events = self
+            .responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| LLMError::LLMProvider("MockProvider has no response left".to_owned()))?
+            .map_err(LLMError::LLMProvider)?;
+        let cancel = cancel.clone();
+        let delay = self.delay;
+        let stream = async_stream::stream!({
+            for event in events {
+                if let Some(delay) = delay {
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = cancel.cancelled() => {}
+                    }
+                }
+                if cancel.is_cancelled() {
+                    break;
+                }
+                match event {
+                    MockEvent::Chunk(chunk) => yield Ok(chunk),
+                    MockEvent::Error(message) => yield Err(LLMError::LLMProvider(message)),
+                }
+            }
+            if cancel.is_cancelled() {
+                yield Err(LLMError::Cancelled);
+            }
+        });
+        Ok(Box::pin(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{context::Context, stream_message::StreamMessage},
+        llm::{chat, chat_with_usage, model::ChatMessageRole, tool::ToolRegistry},
+    };
+    use serde::{Deserialize, Serialize};
+    use tokio_stream::StreamExt;
+
+    #[derive(Serialize, Deserialize, schemars::JsonSchema)]
+    pub struct GetWeatherParams {
+        pub location: String,
+    }
+
+    fn get_weather(params: GetWeatherParams) -> serde_json::Value {
+        serde_json::Value::String(format!("The weather in {} is sunny.", params.location))
+    }
+
+    #[tokio::test]
+    async fn test_mock_tool_loop() {
+        let provider = MockProvider::new()
+            .with_response(vec![
+                MockEvent::tool_call("call_1", "get_weather", "{\"loca"),
+                MockEvent::tool_arguments("tion\": \"Hang"),
+                MockEvent::tool_arguments("Zhou\"}"),
+                MockEvent::finish(FinishReason::ToolCalls, Some(20)),
+            ])
+            .with_response(vec![
+                MockEvent::reasoning("the tool said sunny"),
+                MockEvent::content("It is "),
+                MockEvent::content("sunny."),
+                MockEvent::finish(FinishReason::Stop, Some(30)),
+            ]);
+        let mut registry = ToolRegistry::new();
+        registry.register::<GetWeatherParams, _>("get_weather", "get the weather", get_weather);
+
+        let context = Context::new();
+        let listener = context.listen_stream("llm");
+        let messages = vec![ChatMessage::user("How is the weather in HangZhou?")];
+        let (content, tokens) = chat_with_usage(
+            messages,
+            context.stream("llm"),
+            &provider,
+            &registry,
+            context.cancellation_token(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(content, "It is sunny.");
+        assert_eq!(tokens, 50);
+        assert_eq!(provider.remaining(), 0);
+
+        let received = provider.received();
+        assert_eq!(received.len(), 2);
+        let tool_message = received[1].last().unwrap();
+        assert!(matches!(tool_message.role, ChatMessageRole::Tool));
+        assert_eq!(tool_message.tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(
+            tool_message.content,
+            "\"The weather in HangZhou is sunny.\""
+        );
+
+        drop(context);
+        let messages = listener.map(|msg| msg.message).collect::<Vec<_>>().await;
+        assert!(messages.contains(&StreamMessage::ToolCall {
+            id: "call_1".to_owned(),
+            name: "get_weather".to_owned(),
+            arguments: serde_json::json!({"location": "HangZhou"}),
+        }));
+        assert!(messages.contains(&StreamMessage::Thinking("the tool said sunny".to_owned())));
+    }
+
+    #[tokio::test]
+    async fn test_mock_tool_calls_at() {
+        let provider = MockProvider::new().with_response(vec![
+            MockEvent::tool_call_at(0, "call_1", "get_weather", "{\"location\": "),
+            MockEvent::tool_call_at(1, "call_2", "get_weather", "{\"location\": "),
+            MockEvent::tool_arguments_at(1, "\"Paris\"}"),
+            MockEvent::tool_arguments_at(0, "\"HangZhou\"}"),
+            MockEvent::finish(FinishReason::ToolCalls, None),
+        ]);
+        let chunks = provider
+            .chat_stream(&[], &CancellationToken::new())
+            .await
+            .unwrap()
+            .filter_map(|chunk| match chunk.unwrap().delta {
+                ChatMessageDelta::ToolCalls(call) => Some(call),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .await;
+        let indices = chunks.iter().map(|call| call.index).collect::<Vec<_>>();
+        assert_eq!(indices, [0, 1, 1, 0]);
+        assert_eq!(chunks[1].id.as_deref(), Some("call_2"));
+        assert_eq!(chunks[3].id, None);
+        assert_eq!(chunks[3].function.arguments, "\"HangZhou\"}");
+    }
+
+    #[tokio::test]
+    async fn test_mock_errors() {
+        let provider = MockProvider::new()
+            .with_failed_request("rate limited")
+            .with_response(vec![
+                MockEvent::content("Hel"),
+                MockEvent::error("broken pipe"),
+            ])
+            .with_answer("never read")
+            .with_delay(Duration::from_millis(20));
+        let context = Context::new();
+        let cancel = context.cancellation_token().clone();
+        let ask = || vec![ChatMessage::user("Hi")];
+
+        let err = chat(
+            ask(),
+            context.stream("llm"),
+            &provider,
+            &ToolRegistry::new(),
+            &cancel,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "LLMError Provider: rate limited");
+        let err = chat(
+            ask(),
+            context.stream("llm"),
+            &provider,
+            &ToolRegistry::new(),
+            &cancel,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "LLMError Provider: broken pipe");
+
+        cancel.cancel();
+        let err = chat(
+            ask(),
+            context.stream("llm"),
+            &provider,
+            &ToolRegistry::new(),
+            &cancel,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, LLMError::Cancelled));
+        assert_eq!(provider.received().len(), 3);
+    }
+}
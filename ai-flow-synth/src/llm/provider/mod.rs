@@ -1,18 +1,25 @@
+pub mod cassette;
 pub mod deepseek;
+pub mod mock;
 pub mod openai;
 
 use super::{
-    error::LLMResult,
+    error::{LLMError, LLMResult},
     model::{ChatMessage, ChatMessageChunk},
 };
-use futures::Stream;
-use std::pin::Pin;
+use cassette::CassettePlayer;
+use futures::{Stream, StreamExt};
+use reqwest_eventsource::{Event, RequestBuilderExt};
+use std::{pin::Pin, sync::Arc};
+use tokio_util::sync::CancellationToken;
 
 #[async_trait::async_trait]
 pub trait LLMProvider {
+    /// The stream ends with `LLMError::Cancelled` once `cancel` is cancelled.
     async fn chat_stream(
         &self,
         messages: &[ChatMessage],
+        cancel: &CancellationToken,
     ) -> LLMResult<Pin<Box<dyn Stream<Item = LLMResult<ChatMessageChunk>> + Send>>>;
 }
 
@@ -22,3 +29,36 @@ pub enum LLMCallProcess {
     FunctionCall,
     Finish,
 }
+
+/// the `data` of the server-sent events of a response
+pub(crate) type EventData = Pin<Box<dyn Stream<Item = LLMResult<String>> + Send>>;
+
+/// Send the request `body` to `provider`, or answer it from the cassette.
+/// The stream ends after the first error, with `LLMError::LLMProvider`.
+pub(crate) fn event_data(
+    request: reqwest::RequestBuilder,
+    provider: &str,
+    body: serde_json::Value,
+    cassette: Option<&Arc<CassettePlayer>>,
+) -> LLMResult<EventData> {
+    let call = || -> LLMResult<EventData> {
+        let mut event_source = request.json(&body).eventsource()?;
+        Ok(Box::pin(async_stream::stream!({
+            while let Some(event) = event_source.next().await {
+                match event {
+                    Ok(Event::Open) => continue,
+                    Ok(Event::Message(message)) => yield Ok(message.data),
+                    Err(err) => {
+                        yield Err(LLMError::LLMProvider(err.to_string()));
+                        break;
+                    }
+                }
+            }
+            event_source.close();
+        })))
+    };
+    match cassette {
+        Some(cassette) => cassette.events(provider, &body, call),
+        None => call(),
+    }
+}
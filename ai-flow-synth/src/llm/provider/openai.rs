@@ -1,9 +1,9 @@
 #![allow(dead_code)]
-use std::pin::Pin;
+use std::{pin::Pin, sync::Arc};
 
 use futures::{Stream, StreamExt};
-use reqwest_eventsource::RequestBuilderExt;
 use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
 
 use crate::llm::{
     error::{LLMError, LLMResult},
@@ -13,7 +13,7 @@ use crate::llm::{
     },
 };
 
-use super::LLMProvider;
+use super::{LLMProvider, cassette::CassettePlayer, event_data};
 
 pub struct OpenAIClient {
     client: reqwest::Client,
@@ -21,6 +21,7 @@ pub struct OpenAIClient {
     base_url: String,
     model: String,
     tools: Vec<serde_json::Value>,
+    cassette: Option<Arc<CassettePlayer>>,
     // maybe other fields...
 }
 
@@ -33,9 +34,16 @@ impl OpenAIClient {
             base_url,
             model,
             tools: Vec::new(),
+            cassette: None,
         }
     }
 
+    /// record or replay the responses with `cassette`
+    pub fn with_cassette(mut self, cassette: Arc<CassettePlayer>) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
     pub fn add_tool(&mut self, tool: serde_json::Value) {
         self.tools.push(tool);
     }
@@ -56,47 +64,51 @@ impl LLMProvider for OpenAIClient {
     async fn chat_stream(
         &self,
         messages: &[ChatMessage],
+        cancel: &CancellationToken,
     ) -> LLMResult<Pin<Box<dyn Stream<Item = LLMResult<ChatMessageChunk>> + Send>>> {
         // Make the request to the OpenAI API
-        let response = self
+        let request = self
             .client
             .post(format!("{}/v1/chat/completions", self.base_url))
-            .bearer_auth(&self.api_key)
-            .json(&serde_json::json!(
-                {
-                    "model": self.model,
-                    "messages": messages,
-                    "stream": true,
-                    "tools": self.tools,
-                }
-            ))
-            .eventsource()?;
+            .bearer_auth(&self.api_key);
+        let body = serde_json::json!(
+            {
+                "model": self.model,
+                "messages": messages,
+                "stream": true,
+                "tools": self.tools,
+            }
+        );
+        let response = event_data(request, "openai", body, self.cassette.as_ref())?;
+        let cancel = cancel.clone();
         let stream = async_stream::stream!({
             let mut response = response;
-            while let Some(event) = response.next().await {
-                let event = event
-                    .map_err(|err| LLMError::LLMProvider(format!("OpenAI API error: {}", err)))?;
-                let chunk: ChatMessageChunk = match event {
-                    reqwest_eventsource::Event::Open => {
-                        continue; // Open event, we can ignore it
-                    }
-                    reqwest_eventsource::Event::Message(event) => {
-                        let data = event.data;
-                        tracing::info!("Receive OpenAI API chunk: {}", data);
-                        if data.trim() == "[DONE]" {
-                            tracing::info!("OpenAI API stream DONE");
-                            break;
-                        } else if let Ok(chunk) = serde_json::from_str::<OpenAIChunkResp>(&data) {
-                            chunk.into()
-                        } else {
-                            tracing::error!("OpenAI API response is not valid JSON: {}", data);
-                            continue; // Skip this chunk
-                        }
+            while let Some(data) = tokio::select! {
+                data = response.next() => data,
+                _ = cancel.cancelled() => None,
+            } {
+                let data = data.map_err(|err| match err {
+                    LLMError::LLMProvider(err) => {
+                        LLMError::LLMProvider(format!("OpenAI API error: {}", err))
                     }
+                    err => err,
+                })?;
+                tracing::info!("Receive OpenAI API chunk: {}", data);
+                let chunk: ChatMessageChunk = if data.trim() == "[DONE]" {
+                    tracing::info!("OpenAI API stream DONE");
+                    break;
+                } else if let Ok(chunk) = serde_json::from_str::<OpenAIChunkResp>(&data) {
+                    chunk.into()
+                } else {
+                    tracing::error!("OpenAI API response is not valid JSON: {}", data);
+                    continue; // Skip this chunk
                 };
                 tracing::info!("Yielding chunk: {:?}", chunk);
                 yield Ok(chunk);
             }
+            if cancel.is_cancelled() {
+                yield Err(LLMError::Cancelled);
+            }
         });
         Ok(Box::pin(stream))
     }
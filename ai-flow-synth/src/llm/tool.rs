@@ -1,10 +1,10 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
-pub type ToolFn = Box<dyn Fn(serde_json::Value) -> serde_json::Value + Send + Sync>;
+pub type ToolFn = Arc<dyn Fn(serde_json::Value) -> serde_json::Value + Send + Sync>;
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct ToolRegistry {
     pub map: HashMap<&'static str, (ToolFn, &'static str, serde_json::Value)>,
 }
@@ -21,13 +21,24 @@ impl ToolRegistry {
     {
         let schema = schemars::schema_for!(T);
 
-        let wrapper: ToolFn = Box::new(move |value: serde_json::Value| {
+        let wrapper: ToolFn = Arc::new(move |value: serde_json::Value| {
             let input: T = serde_json::from_value(value).expect("Failed to deserialize input");
             f(input)
         });
         self.map.insert(name, (wrapper, desc, schema.to_value()));
     }
 
+    /// the tools named in `names` that are registered, sharing their functions
+    pub fn subset(&self, names: &[String]) -> ToolRegistry {
+        let map = self
+            .map
+            .iter()
+            .filter(|(name, _)| names.iter().any(|n| n == *name))
+            .map(|(name, tool)| (*name, tool.clone()))
+            .collect();
+        ToolRegistry { map }
+    }
+
     pub fn get(&self, name: &str) -> Option<&(ToolFn, &'static str, serde_json::Value)> {
         self.map.get(name)
     }
@@ -62,6 +73,14 @@ impl ToolRegistry {
     }
 }
 
+impl std::fmt::Debug for ToolRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ToolRegistry")
+            .field("tools", &self.map.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[allow(unused_imports)]
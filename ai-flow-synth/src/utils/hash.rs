@@ -0,0 +1,10 @@
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of `data` as hex, stable across runs and platforms unlike `DefaultHasher`,
+/// for keys that are persisted.
+pub fn stable_hash(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
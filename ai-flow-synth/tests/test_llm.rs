@@ -28,18 +28,18 @@ async fn test_llm_function_call() -> anyhow::Result<()> {
     registry.register::<GetWeatherParams, _>("get_weather", "获取天气", get_weather);
 
     client.add_tools(registry.export_all_tools());
-    let mut context = Context::new();
+    let context = Context::new();
     let mut listener = context.listen();
     tokio::spawn(async move {
         while let Some(msg) = listener.next().await {
-            match msg {
-                Ok(StreamMessage::Delta(delta)) => {
+            match msg.message {
+                StreamMessage::Delta(delta) => {
                     println!("Received delta: {}", delta);
                 }
-                Ok(StreamMessage::Procedure(proc)) => {
+                StreamMessage::Procedure(proc) => {
                     println!("Received procedure: {}", proc);
                 }
-                Err(e) => eprintln!("Error receiving message: {}", e),
+                message => println!("Received event: {:?}", message),
             }
         }
     });
@@ -51,7 +51,15 @@ async fn test_llm_function_call() -> anyhow::Result<()> {
             // "Hi, would you please tell me what the time is it now, and weather in HangZhou",
         ),
     ];
-    let final_result = chat(messages, context.stream("stream_id"), &client, &registry).await?;
+    let cancel = context.cancellation_token().clone();
+    let final_result = chat(
+        messages,
+        context.stream("stream_id"),
+        &client,
+        &registry,
+        &cancel,
+    )
+    .await?;
 
     println!("Final result: {}", final_result);
     Ok(())
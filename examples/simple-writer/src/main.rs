@@ -23,11 +23,8 @@ async fn main() {
 
     let mut listener = context.listen();
     tokio::spawn(async move {
-        while let Some(message) = listener.next().await {
-            match message {
-                Ok(msg) => println!("Received message: {:?}", msg),
-                Err(e) => eprintln!("Error receiving message: {:?}", e),
-            }
+        while let Some(msg) = listener.next().await {
+            println!("Received message: {:?}", msg);
         }
     });
 
@@ -15,21 +15,16 @@ use serde_json::Value;
 
 use tokio_stream::StreamExt;
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Status)]
 pub enum JobStatus {
-    #[default]
+    #[status(default)]
     NotStarted,
+    #[status(failed)]
     Failed,
     Written,
     Finished,
 }
 
-impl Status for JobStatus {
-    fn failed() -> Self {
-        JobStatus::Failed
-    }
-}
-
 pub struct WriterNode {
     prompt: String,
 }
@@ -53,12 +48,14 @@ impl Node for WriterNode {
             ChatMessage::system("You are a professional writer."),
             ChatMessage::user(self.prompt.clone()),
         ];
-        let mut chat_stream = client.chat_stream(&messages).await?;
+        let mut chat_stream = client
+            .chat_stream(&messages, context.cancellation_token())
+            .await?;
         let mut content = String::new();
         while let Some(chunk) = chat_stream.next().await {
             let chunk = chunk?;
             content.push_str(&chunk.delta_content);
-            stream.send(StreamMessage::Delta(chunk.delta_content.clone()))?;
+            stream.send(StreamMessage::Delta(chunk.delta_content.clone()));
         }
         println!("Received content: {}", content);
         context.set("draft", serde_json::Value::String(content.clone()));
@@ -71,10 +68,7 @@ impl Node for WriterNode {
         result: &Result<Value>,
     ) -> Result<NodeResult<Self::FlowStatus>> {
         println!("after_exec: {:?}", result);
-        Ok(NodeResult {
-            status: JobStatus::Written,
-            message: "done".to_owned(),
-        })
+        Ok(NodeResult::new(JobStatus::Written, "done"))
     }
 }
 pub struct EditorNode {
@@ -110,12 +104,14 @@ impl Node for EditorNode {
             ChatMessage::user(content.to_string()),
         ];
 
-        let mut chat_stream = client.chat_stream(&messages).await?;
+        let mut chat_stream = client
+            .chat_stream(&messages, context.cancellation_token())
+            .await?;
         let mut content = String::new();
         while let Some(chunk) = chat_stream.next().await {
             let chunk = chunk?;
             content.push_str(&chunk.delta_content);
-            stream.send(StreamMessage::Delta(chunk.delta_content.clone()))?;
+            stream.send(StreamMessage::Delta(chunk.delta_content.clone()));
         }
         println!("Received content: {}", content);
         context.set("result", serde_json::Value::String(content.clone()));
@@ -128,9 +124,6 @@ impl Node for EditorNode {
         result: &Result<Value>,
     ) -> Result<NodeResult<Self::FlowStatus>> {
         println!("after_exec: {:?}", result);
-        Ok(NodeResult {
-            status: JobStatus::Finished,
-            message: "done".to_owned(),
-        })
+        Ok(NodeResult::new(JobStatus::Finished, "done"))
     }
 }
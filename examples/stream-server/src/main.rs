@@ -1,17 +1,35 @@
 mod node;
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 
-use ai_flow_synth::core::stream_message::StreamMessage;
-use ai_flow_synth::{core::context::Context, flow};
+use ai_flow_synth::{
+    core::{context::Context, event_log::EventLog},
+    flow,
+};
 
-use futures_util::StreamExt;
+use futures_util::{Stream, StreamExt};
 use node::{EditorNode, JobStatus, WriterNode};
 
 use serde::{Deserialize, Serialize};
 
 use salvo::prelude::*;
 use salvo::sse::{SseEvent, SseKeepAlive};
+use tokio_util::sync::CancellationToken;
+
+/// how long a finished run can still be resumed
+const FINISHED_RUN_TTL: Duration = Duration::from_secs(60);
+
+struct Run {
+    events: EventLog,
+    cancel: CancellationToken,
+}
+
+/// the runs by stream id, the event log does not keep the run's streams open
+static RUNS: LazyLock<parking_lot::Mutex<HashMap<String, Run>>> = LazyLock::new(Default::default);
 
 #[tokio::main]
 async fn main() {
@@ -19,7 +37,9 @@ async fn main() {
 
     let router = Router::new()
         .goal(index)
-        .push(Router::with_path("test").post(llm_chat));
+        .push(Router::with_path("test").post(llm_chat))
+        .push(Router::with_path("resume/{stream_id}").get(resume_stream))
+        .push(Router::with_path("stop/{stream_id}").post(stop_stream));
 
     let acceptor = TcpListener::new("0.0.0.0:5800").bind().await;
     Server::new(acceptor).serve(router).await;
@@ -49,26 +69,20 @@ async fn llm_chat(req: &mut Request, res: &mut Response) {
         edges: [("start", JobStatus::Written, "editor")]
     );
 
-    let listener = context.listen();
-    let stream = listener.map(|msg| match msg {
-        Ok(msg) => {
-            tracing::info!("Received message: {:?}", msg);
-            match msg {
-                StreamMessage::Delta(delta) => {
-                    // tracing::info!("Delta: {:?}", delta);
-                    Ok::<_, salvo::Error>(SseEvent::default().text(delta))
-                } // _ => Ok::<_, salvo::Error>(SseEvent::default().text("")),
-                StreamMessage::Procedure(proc) => {
-                    tracing::info!("Procedure: {:?}", proc);
-                    Ok::<_, salvo::Error>(SseEvent::default().text(proc))
-                }
-            }
-        }
-        Err(e) => {
-            tracing::error!("Error receiving message: {:?}", e);
-            Err(salvo::Error::Other(Box::new(e)))
-        }
-    });
+    // the run goes on when the page is closed, it can resume or stop it by the stream id
+    let stream_id = context.id().to_owned();
+    RUNS.lock().insert(
+        stream_id.clone(),
+        Run {
+            events: context.event_log(),
+            cancel: context.cancellation_token().clone(),
+        },
+    );
+    let ready = SseEvent::default()
+        .name("ready")
+        .text(serde_json::json!({ "stream_id": stream_id }).to_string());
+    let stream =
+        futures_util::stream::once(async { Ok(ready) }).chain(sse_events(&context.event_log(), 0));
     SseKeepAlive::new(stream).stream(res);
     tokio::spawn(async move {
         let result = flow.run(context.clone()).await;
@@ -82,9 +96,55 @@ async fn llm_chat(req: &mut Request, res: &mut Response) {
             Some(value) => println!("Result: {:?}", value),
             None => eprintln!("No result found"),
         }
+        drop(context);
+        tokio::time::sleep(FINISHED_RUN_TTL).await;
+        RUNS.lock().remove(&stream_id);
     });
 }
 
+/// Replays a run from `?from=` (or after the `Last-Event-ID`), then follows it live.
+#[handler]
+async fn resume_stream(req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
+    let stream_id = req.param::<String>("stream_id").unwrap_or_default();
+    let from = req
+        .query::<u64>("from")
+        .or_else(|| req.header::<u64>("Last-Event-ID").map(|seq| seq + 1))
+        .unwrap_or(0);
+    let events = RUNS
+        .lock()
+        .get(&stream_id)
+        .map(|run| run.events.clone())
+        .ok_or_else(StatusError::not_found)?;
+    tracing::info!("resume_stream: {stream_id} from {from}");
+    SseKeepAlive::new(sse_events(&events, from)).stream(res);
+    Ok(())
+}
+
+#[handler]
+async fn stop_stream(req: &mut Request) -> Result<(), StatusError> {
+    let stream_id = req.param::<String>("stream_id").unwrap_or_default();
+    let runs = RUNS.lock();
+    let run = runs.get(&stream_id).ok_or_else(StatusError::not_found)?;
+    run.cancel.cancel();
+    Ok(())
+}
+
+/// one sse event per message, the seq is the event id a client resumes after
+fn sse_events(
+    events: &EventLog,
+    from: u64,
+) -> impl Stream<Item = Result<SseEvent, salvo::Error>> + Send + 'static {
+    events.listen_from(from).map(|msg| {
+        tracing::info!("Received message: {:?}", msg);
+        // the node in the payload tells the writer's tokens from the editor's
+        let event = SseEvent::default()
+            .id(msg.seq.to_string())
+            .name(msg.message.event_name());
+        let data = serde_json::to_string(&msg).map_err(|e| salvo::Error::Other(Box::new(e)))?;
+        Ok(event.text(data))
+    })
+}
+
 #[handler]
 async fn index(res: &mut Response) {
     res.render(Text::Html(INDEX_HTML));
@@ -5,7 +5,7 @@ use ai_flow_synth::{
         status::Status,
     },
     llm::{
-        chat,
+        chat_with_usage,
         model::ChatMessage,
         provider::{self},
         tool::ToolRegistry,
@@ -14,21 +14,16 @@ use ai_flow_synth::{
 use anyhow::Result;
 use serde_json::Value;
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Status)]
 pub enum JobStatus {
-    #[default]
+    #[status(default)]
     NotStarted,
+    #[status(failed)]
     Failed,
     Written,
     Finished,
 }
 
-impl Status for JobStatus {
-    fn failed() -> Self {
-        JobStatus::Failed
-    }
-}
-
 pub struct WriterNode {
     prompt: String,
 }
@@ -53,7 +48,10 @@ impl Node for WriterNode {
             ChatMessage::user(self.prompt.clone()),
         ];
         let registry = ToolRegistry::default();
-        let result = chat(messages, stream, &client, &registry).await?;
+        let cancel = context.cancellation_token().clone();
+        let (result, tokens) =
+            chat_with_usage(messages, stream, &client, &registry, &cancel).await?;
+        context.add_token_usage(tokens);
         println!("Chat result: {}", result);
         context.set("draft", serde_json::Value::String(result));
 
@@ -65,10 +63,7 @@ impl Node for WriterNode {
         result: &Result<Value>,
     ) -> Result<NodeResult<Self::FlowStatus>> {
         println!("after_exec: {:?}", result);
-        Ok(NodeResult {
-            status: JobStatus::Written,
-            message: "done".to_owned(),
-        })
+        Ok(NodeResult::new(JobStatus::Written, "done"))
     }
 }
 pub struct EditorNode {
@@ -88,11 +83,7 @@ impl Node for EditorNode {
     async fn execute(&self, context: &mut Context) -> Result<Value> {
         // println!("prompt: {}", self.prompt);
         let stream = context.stream("editor_stream");
-        let content = context.get("draft").unwrap_or(&serde_json::Value::Null);
-        if content.is_null() {
-            return Err(anyhow::anyhow!("draft not found"));
-        }
-        let content = content.as_str().unwrap_or("");
+        let content: String = context.get_as("draft")?;
         println!("content: {}", content);
         println!("editing...");
 
@@ -102,10 +93,13 @@ impl Node for EditorNode {
                 "You are a professional editor. Find the mistakes in the text and correct them. {} return the result text ONLY.",
                 self.prompt
             )),
-            ChatMessage::user(content.to_string()),
+            ChatMessage::user(content),
         ];
         let registry = ToolRegistry::default();
-        let result = chat(messages, stream, &client, &registry).await?;
+        let cancel = context.cancellation_token().clone();
+        let (result, tokens) =
+            chat_with_usage(messages, stream, &client, &registry, &cancel).await?;
+        context.add_token_usage(tokens);
         println!("Chat result: {}", result);
         context.set("result", serde_json::Value::String(result));
 
@@ -117,9 +111,6 @@ impl Node for EditorNode {
         result: &Result<Value>,
     ) -> Result<NodeResult<Self::FlowStatus>> {
         println!("after_exec: {:?}", result);
-        Ok(NodeResult {
-            status: JobStatus::Finished,
-            message: "done".to_owned(),
-        })
+        Ok(NodeResult::new(JobStatus::Finished, "done"))
     }
 }
@@ -0,0 +1,137 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ai_flow_synth::{
+    core::{
+        agent::{AGENT_INPUT, AGENT_LLM, Agent, AgentRegistry},
+        context::{CONTEXT_RESULT, Context},
+        node::Node,
+        status::Status,
+        template::NodeRegistry,
+    },
+    llm::{
+        chat_with_usage,
+        model::ChatMessage,
+        provider::{deepseek::DeepSeekClient, openai::OpenAIClient},
+        tool::ToolRegistry,
+    },
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// the agents of the paper scenarios, one toml file each
+static AGENTS: [&str; 2] = [
+    include_str!("summarize.toml"),
+    include_str!("translate.toml"),
+];
+
+#[derive(Debug, Clone, PartialEq, Status)]
+pub enum PaperStatus {
+    #[status(default)]
+    Done,
+    #[status(failed)]
+    Failed,
+}
+
+/// the llm providers `LlmNode` can create a client for
+static PROVIDERS: [&str; 2] = ["deepseek", "openai"];
+
+/// `api_keys` are the keys of the llm providers by name, see `BackendConfig::llm_api_keys`
+pub fn create_agent_registry(
+    api_keys: &HashMap<String, String>,
+) -> anyhow::Result<AgentRegistry<PaperStatus>> {
+    let api_keys = Arc::new(api_keys.clone());
+    let mut nodes = NodeRegistry::new();
+    nodes.register("llm", move |params: LlmParams| {
+        Arc::new(LlmNode {
+            params,
+            api_keys: api_keys.clone(),
+        }) as Arc<dyn Node<FlowStatus = PaperStatus>>
+    });
+    let mut registry = AgentRegistry::new(nodes, Arc::new(ToolRegistry::new()));
+    for agent in AGENTS {
+        let agent = Agent::from_toml(agent)?;
+        let provider = agent.llm.provider.as_deref().unwrap_or(PROVIDERS[0]);
+        if !PROVIDERS.contains(&provider) {
+            anyhow::bail!(
+                "agent '{}' uses unknown llm provider '{provider}'",
+                agent.id
+            );
+        }
+        registry.register(agent)?;
+    }
+    Ok(registry)
+}
+
+#[derive(Debug, Deserialize)]
+struct LlmParams {
+    prompt: String,
+    /// work on the result of the previous node instead of the input text
+    #[serde(default)]
+    from_result: bool,
+}
+
+/// Asks the agent's llm to apply `prompt` to the text, answers in the input `language`.
+/// The llm can call the agent's tools.
+struct LlmNode {
+    params: LlmParams,
+    api_keys: Arc<HashMap<String, String>>,
+}
+
+#[async_trait::async_trait]
+impl Node for LlmNode {
+    type FlowStatus = PaperStatus;
+
+    async fn execute(&self, context: &mut Context) -> anyhow::Result<Value> {
+        let input = context.get_key(&AGENT_INPUT)?;
+        let llm = context.get_key(&AGENT_LLM)?;
+        let text = if self.params.from_result {
+            context.get_as::<String>(CONTEXT_RESULT)?
+        } else {
+            input["text"].as_str().unwrap_or_default().to_owned()
+        };
+        let mut prompt = self.params.prompt.clone();
+        if let Some(language) = input["language"].as_str() {
+            prompt.push_str(&format!(" Answer in {language}."));
+        }
+
+        let provider = llm.provider.as_deref().unwrap_or(PROVIDERS[0]);
+        let api_key = self.api_keys.get(provider).cloned().ok_or_else(|| {
+            anyhow::anyhow!("no api key for the llm provider '{provider}' in the backend config")
+        })?;
+        let mut messages = vec![];
+        if let Some(system_prompt) = llm.system_prompt {
+            messages.push(ChatMessage::system(system_prompt));
+        }
+        messages.push(ChatMessage::user(format!("{prompt}\n\n{text}")));
+
+        let stream = context.stream("llm");
+        let cancel = context.cancellation_token().clone();
+        let tools = context.tools().clone();
+        let (content, tokens) = match provider {
+            "openai" => {
+                let mut client = OpenAIClient::new(
+                    api_key,
+                    llm.base_url
+                        .unwrap_or_else(|| "https://api.openai.com".to_owned()),
+                    llm.model.unwrap_or_else(|| "gpt-4o-mini".to_owned()),
+                );
+                for tool in tools.export_all_tools() {
+                    client.add_tool(tool);
+                }
+                chat_with_usage(messages, stream, &client, &tools, &cancel).await?
+            }
+            _ => {
+                let mut client = DeepSeekClient::new(
+                    api_key,
+                    llm.base_url
+                        .unwrap_or_else(|| "https://api.deepseek.com".to_owned()),
+                    llm.model.unwrap_or_else(|| "deepseek-chat".to_owned()),
+                );
+                client.add_tools(tools.export_all_tools());
+                chat_with_usage(messages, stream, &client, &tools, &cancel).await?
+            }
+        };
+        context.add_token_usage(tokens);
+        Ok(Value::from(content))
+    }
+}
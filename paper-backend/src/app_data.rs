@@ -1,12 +1,17 @@
 use std::sync::Arc;
 
-use ai_flow_synth::utils::MongoClient;
+use ai_flow_synth::{core::agent::AgentRegistry, utils::MongoClient};
 
-use crate::{config::Config, model::create_all_index};
+use crate::{
+    agent::{PaperStatus, create_agent_registry},
+    config::Config,
+    model::create_all_index,
+};
 
 #[derive(Debug)]
 pub struct AppData {
     pub mongo_client: MongoClient,
+    pub agents: AgentRegistry<PaperStatus>,
 }
 
 pub type AppDataRef = Arc<AppData>;
@@ -25,6 +30,12 @@ impl AppData {
         //     .await
         //     .expect("Failed to create indexes");
 
-        Arc::new(AppData { mongo_client })
+        let agents = create_agent_registry(&config.backend_config.llm_api_keys)
+            .expect("Failed to load agents");
+
+        Arc::new(AppData {
+            mongo_client,
+            agents,
+        })
     }
 }
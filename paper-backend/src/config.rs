@@ -1,5 +1,6 @@
 use ai_flow_synth::utils::{LogConfig, MongoConfig};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
@@ -28,6 +29,9 @@ pub struct FrontendConfig {
 pub struct BackendConfig {
     pub address: String,
     pub jwt: Jwt,
+    /// the api key of each llm provider the agents use, by provider name
+    #[serde(default)]
+    pub llm_api_keys: HashMap<String, String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -1,3 +1,4 @@
+mod agent;
 mod app_data;
 mod config;
 mod error;
@@ -0,0 +1,125 @@
+use futures::StreamExt;
+use salvo::{
+    Depot, Response, Router, Scribe, Writer,
+    oapi::{
+        RouterExt, ToResponse, ToSchema, endpoint,
+        extract::{JsonBody, PathParam},
+    },
+    sse::{SseEvent, SseKeepAlive},
+    writing::Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    app_data::AppDataRef,
+    error::{ServiceError, ServiceResult},
+};
+
+pub fn create_router() -> Router {
+    Router::new()
+        .push(Router::new().get(list_agents))
+        .push(Router::with_path("{agent_id}/run").post(run_agent))
+        .oapi_tag("agent")
+}
+
+/// Response schema for an agent.
+#[derive(Debug, Serialize, Deserialize, ToSchema, ToResponse)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentResponse {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value,
+    pub output_schema: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToResponse, ToSchema)]
+pub struct ListAgentsResponse(pub Vec<AgentResponse>);
+
+impl Scribe for ListAgentsResponse {
+    fn render(self, res: &mut Response) {
+        res.render(Json(self));
+    }
+}
+
+/// List Agents
+///
+/// Lists the agents of the paper scenarios, with the schema of their input and output.
+#[endpoint(
+    status_codes(200, 401),
+    responses(
+        (status_code = 200, body = ListAgentsResponse, description = "List of agents"),
+        (status_code = 401, description = "Unauthorized: User not authenticated")
+    )
+)]
+async fn list_agents(depot: &mut Depot) -> ServiceResult<ListAgentsResponse> {
+    let state = depot.obtain::<AppDataRef>()?;
+    Ok(ListAgentsResponse(
+        state
+            .agents
+            .list()
+            .into_iter()
+            .map(|agent| AgentResponse {
+                id: agent.id.clone(),
+                name: agent.name.clone(),
+                description: agent.description.clone(),
+                input_schema: agent.input_schema.clone(),
+                output_schema: agent.output_schema.clone(),
+            })
+            .collect(),
+    ))
+}
+
+/// Run Agent
+///
+/// Runs the agent on the input and streams its messages as server-sent events,
+/// the last event is `result` with the output or the error.
+#[endpoint(
+    status_codes(200, 400, 401, 404),
+    responses(
+        (status_code = 200, description = "Server-sent events of the run"),
+        (status_code = 400, description = "Bad Request: Input does not match the schema"),
+        (status_code = 401, description = "Unauthorized: User not authenticated"),
+        (status_code = 404, description = "Not Found: Agent does not exist")
+    )
+)]
+async fn run_agent(
+    depot: &mut Depot,
+    agent_id: PathParam<String>,
+    input: JsonBody<Value>,
+    res: &mut Response,
+) -> ServiceResult<()> {
+    let state = depot.obtain::<AppDataRef>()?;
+    let agent = state
+        .agents
+        .get(&agent_id)
+        .ok_or_else(|| ServiceError::NotFound(format!("Agent {} not found", agent_id)))?;
+    let run = agent
+        .run(input.0)
+        .map_err(|e| ServiceError::BadRequest(e.to_string()))?;
+    tracing::info!("run agent {} as {}", agent.agent.id, run.run_id);
+
+    // the run is cancelled once the client is gone
+    let cancel_on_close = run.cancel.clone().drop_guard();
+    let messages = run.events.map(|msg| {
+        let data = serde_json::to_string(&msg).map_err(|e| salvo::Error::Other(Box::new(e)))?;
+        Ok::<_, salvo::Error>(
+            SseEvent::default()
+                .id(msg.seq.to_string())
+                .name(msg.message.event_name())
+                .text(data),
+        )
+    });
+    let result = futures::stream::once(async move {
+        let _ = &cancel_on_close;
+        let result = match run.result.await {
+            Ok((Ok(output), _)) => serde_json::json!({ "output": output }),
+            Ok((Err(e), _)) => serde_json::json!({ "error": e.to_string() }),
+            Err(e) => serde_json::json!({ "error": e.to_string() }),
+        };
+        Ok(SseEvent::default().name("result").text(result.to_string()))
+    });
+    SseKeepAlive::new(messages.chain(result)).stream(res);
+    Ok(())
+}
@@ -13,6 +13,7 @@ use crate::{
     utils::jwt::JwtClaims,
 };
 
+mod agent;
 mod auth;
 mod folder;
 mod user;
@@ -32,6 +33,7 @@ pub fn create_router(config: &BackendConfig) -> Router {
     let auth_router = Router::new()
         .hoop(auth_handler)
         .hoop(jwt_to_user)
+        .push(Router::with_path("agent").push(agent::create_router()))
         .push(Router::with_path("auth").push(auth::create_router()))
         .push(Router::with_path("folder").push(folder::create_router()))
         .push(Router::with_path("user").push(user::create_router()))